@@ -0,0 +1,181 @@
+use crate::types::*;
+use crate::ROOT_ORDER;
+use std::collections::{BTreeMap, BTreeSet};
+
+/**
+ * Version vectors for every operation, so ancestry can be checked without walking the op graph.
+ * An operation's version vector holds the largest seq from each agent in its history.
+ *
+ * An agent's operations are normally a single chain, each succeeding the last, and succeeds links
+ * are part of an operation's history. So an operation from the agent is in a version's history
+ * exactly when its seq is at most the agent's entry in the version vector. Agents whose
+ * operations fork (succeeding anything other than their latest operation) don't have this
+ * property, and ancestry queries for them have to walk the graph.
+ */
+#[derive(Debug, Default)]
+pub(crate) struct Ancestry {
+    /** Indexed by order. Entries are sorted by agent. */
+    vectors: Vec<Vec<(Agent, Seq)>>,
+    forked: BTreeSet<Agent>,
+}
+
+impl Ancestry {
+    fn seq_in(&self, order: Order, agent: Agent) -> Option<Seq> {
+        let vector = &self.vectors[order as usize];
+        vector.binary_search_by_key(&agent, |(a, _)| *a).ok().map(|i| vector[i].1)
+    }
+
+    /**
+     * Add the next operation. succeeds is the seq of the operation it succeeds, and latest is the
+     * agent's largest seq before this operation.
+     */
+    pub(crate) fn add(&mut self, op: &LocalOperation, succeeds: Option<Seq>, latest: Option<Seq>) {
+        assert_eq!(op.order as usize, self.vectors.len(), "Operations must be added in order");
+        if succeeds != latest || latest.is_some_and(|seq| op.version.seq <= seq) {
+            self.forked.insert(op.version.agent);
+        }
+
+        let mut vector = BTreeMap::<Agent, Seq>::new();
+        let previous = op.parents.iter().chain(op.succeeds.iter())
+            .filter(|o| **o != ROOT_ORDER);
+        for &o in previous {
+            for &(agent, seq) in &self.vectors[o as usize] {
+                let entry = vector.entry(agent).or_insert(seq);
+                *entry = (*entry).max(seq);
+            }
+        }
+        let entry = vector.entry(op.version.agent).or_insert(op.version.seq);
+        *entry = (*entry).max(op.version.seq);
+
+        self.vectors.push(vector.into_iter().collect());
+    }
+
+    /**
+     * Check if the named version is in the history of the branch. None if this can't be answered
+     * from version vectors.
+     */
+    pub(crate) fn branch_contains(&self, target: &LocalVersion, branch: &[Order]) -> Option<bool> {
+        if self.forked.contains(&target.agent) { return None; }
+
+        Some(branch.iter()
+            .filter(|o| **o != ROOT_ORDER)
+            .any(|o| self.seq_in(*o, target.agent).is_some_and(|seq| seq >= target.seq)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: Agent = 0;
+    const B: Agent = 1;
+
+    /** Adds operations to an Ancestry, tracking each agent's latest seq like OpDb does. */
+    #[derive(Default)]
+    struct Builder {
+        ancestry: Ancestry,
+        versions: Vec<LocalVersion>,
+        latest: BTreeMap<Agent, Seq>,
+    }
+
+    impl Builder {
+        /** Add an operation. succeeds is the order of the operation it succeeds. Returns its order. */
+        fn add(&mut self, agent: Agent, seq: Seq, parents: &[Order], succeeds: Option<Order>) -> Order {
+            let order = self.versions.len() as Order;
+            let version = LocalVersion { agent, seq };
+            let op = LocalOperation {
+                order,
+                version,
+                parents: parents.to_vec(),
+                doc_ops: vec!(),
+                succeeds,
+                hash: [0; 32],
+                signature: vec!(),
+                pruned: false,
+            };
+
+            let succeeds_seq = succeeds.map(|o| self.versions[o as usize].seq);
+            self.ancestry.add(&op, succeeds_seq, self.latest.get(&agent).copied());
+            self.versions.push(version);
+            let latest = self.latest.entry(agent).or_insert(seq);
+            *latest = (*latest).max(seq);
+            order
+        }
+
+        fn contains(&self, target: Order, branch: &[Order]) -> Option<bool> {
+            self.ancestry.branch_contains(&self.versions[target as usize], branch)
+        }
+    }
+
+    #[test]
+    fn single_agent() {
+        let mut b = Builder::default();
+        let a0 = b.add(A, 0, &[ROOT_ORDER], None);
+        let a1 = b.add(A, 1, &[a0], Some(a0));
+        let a2 = b.add(A, 2, &[a1], Some(a1));
+
+        assert_eq!(b.contains(a0, &[a2]), Some(true));
+        assert_eq!(b.contains(a1, &[a2]), Some(true));
+        assert_eq!(b.contains(a2, &[a1]), Some(false));
+        assert_eq!(b.contains(a0, &[ROOT_ORDER]), Some(false));
+    }
+
+    #[test]
+    fn concurrent_agents() {
+        let mut b = Builder::default();
+        let a0 = b.add(A, 0, &[ROOT_ORDER], None);
+        let b0 = b.add(B, 0, &[ROOT_ORDER], None);
+        let a1 = b.add(A, 1, &[a0], Some(a0));
+        let b1 = b.add(B, 1, &[a0, b0], Some(b0));
+
+        assert_eq!(b.contains(a0, &[b1]), Some(true));
+        assert_eq!(b.contains(a1, &[b1]), Some(false));
+        assert_eq!(b.contains(b0, &[a1]), Some(false));
+        // Any operation in the branch will do.
+        assert_eq!(b.contains(b0, &[a1, b1]), Some(true));
+        assert_eq!(b.contains(a1, &[a1, b1]), Some(true));
+    }
+
+    #[test]
+    fn sparse_seqs() {
+        let mut b = Builder::default();
+        let a0 = b.add(A, 0, &[ROOT_ORDER], None);
+        let a5 = b.add(A, 5, &[a0], Some(a0));
+        let b0 = b.add(B, 0, &[a0], None);
+        let a9 = b.add(A, 9, &[a5, b0], Some(a5));
+
+        assert_eq!(b.contains(a5, &[a9]), Some(true));
+        assert_eq!(b.contains(a5, &[b0]), Some(false));
+        assert_eq!(b.contains(b0, &[a9]), Some(true));
+    }
+
+    #[test]
+    fn forked_agent_falls_back() {
+        let mut b = Builder::default();
+        let a0 = b.add(A, 0, &[ROOT_ORDER], None);
+        let a1 = b.add(A, 1, &[a0], Some(a0));
+        let b0 = b.add(B, 0, &[a0], None);
+        // This succeeds a0 rather than a1, so A's operations no longer form a chain. Here a2's
+        // version vector covers a1 even though a1 isn't in its history.
+        let a2 = b.add(A, 2, &[a0], Some(a0));
+
+        assert_eq!(b.contains(a1, &[a2]), None);
+        assert_eq!(b.contains(a0, &[b0]), None);
+        // Other agents are unaffected.
+        assert_eq!(b.contains(b0, &[a2]), Some(false));
+    }
+
+    #[test]
+    fn out_of_order_seq_forks() {
+        let mut b = Builder::default();
+        let a5 = b.add(A, 5, &[ROOT_ORDER], None);
+        b.add(A, 3, &[a5], Some(a5));
+        assert_eq!(b.contains(a5, &[a5]), None);
+
+        // So does a second chain starting from scratch.
+        let mut b = Builder::default();
+        b.add(B, 0, &[ROOT_ORDER], None);
+        let b1 = b.add(B, 1, &[ROOT_ORDER], None);
+        assert_eq!(b.contains(b1, &[b1]), None);
+    }
+}
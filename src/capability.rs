@@ -0,0 +1,281 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use serde_json::Value;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/** The kinds of access a capability can grant. */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Subscribe,
+}
+
+const ACCESS_NAMES: [(&str, Access); 3] = [("read", Access::Read), ("write", Access::Write), ("subscribe", Access::Subscribe)];
+
+impl Access {
+    pub fn name(self) -> &'static str {
+        ACCESS_NAMES.iter().find(|(_, a)| *a == self).unwrap().0
+    }
+
+    pub fn parse(name: &str) -> Option<Access> {
+        ACCESS_NAMES.iter().find(|(n, _)| *n == name).map(|(_, a)| *a)
+    }
+}
+
+/**
+ * A restriction on what a capability allows. A capability only allows a request if all of its
+ * caveats do, so adding caveats can narrow a capability but never widen it.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Caveat {
+    /** Only keys starting with the prefix. */
+    Prefix(String),
+    /** Only the listed kinds of access. */
+    Access(Vec<Access>),
+    /** Not after this time, in seconds since the unix epoch. */
+    Expires(u64),
+}
+
+impl fmt::Display for Caveat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Caveat::Prefix(prefix) => write!(f, "prefix={}", prefix),
+            Caveat::Access(access) => write!(f, "access={}", access.iter()
+                .map(|a| a.name())
+                .collect::<Vec<&str>>()
+                .join(",")),
+            Caveat::Expires(time) => write!(f, "expires={}", time),
+        }
+    }
+}
+
+impl Caveat {
+    /** Inverse of to_string. Unknown caveats can't be checked, so they don't parse. */
+    pub fn parse(s: &str) -> Option<Caveat> {
+        let (name, arg) = s.split_once('=')?;
+        match name {
+            "prefix" => Some(Caveat::Prefix(arg.to_string())),
+            "access" => arg.split(',')
+                .filter(|a| !a.is_empty())
+                .map(Access::parse)
+                .collect::<Option<Vec<Access>>>()
+                .map(Caveat::Access),
+            "expires" => arg.parse().ok().map(Caveat::Expires),
+            _ => None,
+        }
+    }
+
+    fn allows(&self, access: Access, key: &str, now: u64) -> bool {
+        match self {
+            Caveat::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            Caveat::Access(allowed) => allowed.contains(&access),
+            Caveat::Expires(time) => now < *time,
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn chain(key: &[u8], data: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac
+}
+
+/**
+ * An unforgeable capability token, in the style of macaroons. A capability with no caveats grants
+ * full access to every key. The signature is an HMAC chain: the ID is signed with the server's
+ * root key, and each caveat is signed with the signature before it. So anyone holding a
+ * capability can attenuate it by adding caveats (see attenuate), but removing a caveat or making
+ * a new capability needs the root key.
+ *
+ * Keys are matched against prefix caveats as plain strings, and presence room names are treated
+ * like document keys.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capability {
+    id: String,
+    caveats: Vec<Caveat>,
+    sig: Vec<u8>,
+}
+
+impl Capability {
+    pub fn mint(root_key: &[u8], id: &str) -> Self {
+        Capability {
+            id: id.to_string(),
+            caveats: vec!(),
+            sig: chain(root_key, id.as_bytes()).finalize().into_bytes().to_vec(),
+        }
+    }
+
+    /** Make a narrower capability by adding a caveat. */
+    pub fn attenuate(&self, caveat: Caveat) -> Self {
+        let sig = chain(&self.sig, caveat.to_string().as_bytes()).finalize().into_bytes().to_vec();
+        let mut caveats = self.caveats.clone();
+        caveats.push(caveat);
+        Capability { id: self.id.clone(), caveats, sig }
+    }
+
+    /** Check the capability was derived from one minted with the root key. */
+    pub fn verify(&self, root_key: &[u8]) -> bool {
+        let mut mac = chain(root_key, self.id.as_bytes());
+        for caveat in &self.caveats {
+            mac = chain(&mac.finalize().into_bytes(), caveat.to_string().as_bytes());
+        }
+        // This compares in constant time.
+        mac.verify_slice(&self.sig).is_ok()
+    }
+
+    /** Check the capability allows a kind of access to a key (or to every key with a prefix). */
+    pub fn allows(&self, access: Access, key: &str, now: u64) -> bool {
+        self.caveats.iter().all(|c| c.allows(access, key, now))
+    }
+
+    /**
+     * Identifies whoever holds this capability (for presence), without giving the token away.
+     * Attenuated capabilities have different holders, so they can be handed out to clients.
+     */
+    pub fn holder(&self) -> String {
+        base64::encode_config(&Sha256::digest(&self.sig)[..16], base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn caveats(&self) -> &[Caveat] {
+        &self.caveats
+    }
+
+    /** Encode the capability as a token, for the Authorization header. */
+    pub fn encode(&self) -> String {
+        let json = serde_json::json!({
+            "id": self.id,
+            "caveats": self.caveats.iter().map(|c| c.to_string()).collect::<Vec<String>>(),
+            "sig": base64::encode(&self.sig),
+        });
+        base64::encode_config(serde_json::to_vec(&json).unwrap(), base64::URL_SAFE_NO_PAD)
+    }
+
+    /** Inverse of encode. This doesn't verify the token. */
+    pub fn decode(token: &str) -> Option<Capability> {
+        let bytes = base64::decode_config(token.trim(), base64::URL_SAFE_NO_PAD).ok()?;
+        let json: Value = serde_json::from_slice(&bytes).ok()?;
+
+        Some(Capability {
+            id: json.get("id")?.as_str()?.to_string(),
+            caveats: json.get("caveats")?.as_array()?.iter()
+                .map(|c| c.as_str().and_then(Caveat::parse))
+                .collect::<Option<Vec<Caveat>>>()?,
+            sig: base64::decode(json.get("sig")?.as_str()?).ok()?,
+        })
+    }
+}
+
+/** Checks capabilities against the server's root key. */
+pub struct Authority {
+    root_key: Vec<u8>,
+}
+
+impl fmt::Debug for Authority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak the root key into logs.
+        f.write_str("Authority")
+    }
+}
+
+impl Default for Authority {
+    fn default() -> Self {
+        let mut root_key = vec![0; 32];
+        getrandom::getrandom(&mut root_key).expect("Could not generate a root key");
+        Authority { root_key }
+    }
+}
+
+impl Authority {
+    pub fn new(root_key: Vec<u8>) -> Self {
+        Authority { root_key }
+    }
+
+    /** A new capability with full access. */
+    pub fn mint(&self) -> Capability {
+        let mut id = [0; 16];
+        getrandom::getrandom(&mut id).expect("Could not generate a capability ID");
+        Capability::mint(&self.root_key, &base64::encode_config(id, base64::URL_SAFE_NO_PAD))
+    }
+
+    /** Decode and verify a token. */
+    pub fn verify(&self, token: &str) -> Option<Capability> {
+        Capability::decode(token).filter(|cap| cap.verify(&self.root_key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_000_000;
+
+    #[test]
+    fn attenuation_narrows() {
+        let authority = Authority::new(b"root key".to_vec());
+        let root = authority.mint();
+        assert!(root.allows(Access::Write, "anything", NOW));
+
+        let docs = root.attenuate(Caveat::Prefix("docs/".to_string()));
+        let reader = docs.attenuate(Caveat::Access(vec!(Access::Read, Access::Subscribe)));
+        assert!(authority.verify(&reader.encode()).is_some());
+
+        assert!(reader.allows(Access::Read, "docs/a", NOW));
+        assert!(reader.allows(Access::Subscribe, "docs/", NOW));
+        assert!(!reader.allows(Access::Write, "docs/a", NOW));
+        assert!(!reader.allows(Access::Read, "secret/a", NOW));
+        // A later caveat can't widen an earlier one.
+        let wider = reader.attenuate(Caveat::Prefix("".to_string()));
+        assert!(!wider.allows(Access::Read, "secret/a", NOW));
+    }
+
+    #[test]
+    fn expiry() {
+        let authority = Authority::new(b"root key".to_vec());
+        let cap = authority.mint().attenuate(Caveat::Expires(NOW));
+        assert!(cap.allows(Access::Read, "a", NOW - 1));
+        assert!(!cap.allows(Access::Read, "a", NOW));
+        // Expired capabilities still verify. They just don't allow anything.
+        assert!(authority.verify(&cap.encode()).is_some());
+    }
+
+    #[test]
+    fn tampered_caveats_rejected() {
+        let authority = Authority::new(b"root key".to_vec());
+        let cap = authority.mint()
+            .attenuate(Caveat::Prefix("docs/".to_string()))
+            .attenuate(Caveat::Expires(NOW));
+
+        let mut removed = cap.clone();
+        removed.caveats.pop();
+        assert!(authority.verify(&removed.encode()).is_none());
+
+        let mut changed = cap.clone();
+        changed.caveats[0] = Caveat::Prefix("".to_string());
+        assert!(authority.verify(&changed.encode()).is_none());
+
+        let mut reordered = cap.clone();
+        reordered.caveats.reverse();
+        assert!(authority.verify(&reordered.encode()).is_none());
+
+        // Capabilities from another server don't verify either.
+        assert!(Authority::new(b"other key".to_vec()).verify(&cap.encode()).is_none());
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let cap = Authority::default().mint()
+            .attenuate(Caveat::Access(vec!(Access::Read)))
+            .attenuate(Caveat::Expires(NOW));
+        assert_eq!(Capability::decode(&cap.encode()), Some(cap));
+        assert_eq!(Capability::decode("not a token"), None);
+    }
+}
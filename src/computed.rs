@@ -0,0 +1,167 @@
+use crate::types::*;
+use crate::op_db::OpDb;
+use crate::view_db::ViewDb;
+use crate::links::{self, Link, Visited};
+use rhai::{Engine, Scope, Dynamic};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+pub(crate) const DEFAULT_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+
+/** Limits for view scripts, so a bad script can't take down the server. */
+const MAX_OPERATIONS: u64 = 1_000_000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_STRING_SIZE: usize = 1 << 20;
+const MAX_COLLECTION_SIZE: usize = 10_000;
+/** The most rendered outputs kept. The least recently used are dropped first. */
+const MAX_CACHE_ENTRIES: usize = 1000;
+
+/**
+ * A computed view. The script is the key of a document containing a [rhai](https://rhai.rs)
+ * script. The script is run with the source document's JSON value in `doc` and its key in `key`,
+ * and its result is the view's output. String results are returned with content_type, and
+ * anything else is returned as JSON.
+ */
+#[derive(Clone, Debug)]
+pub(crate) struct ViewDef {
+    pub script: DocId,
+    pub content_type: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Rendered {
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    /** The versions of the source document and the script the output was rendered from. */
+    versions: Vec<Order>,
+    output: Result<Rendered, String>,
+    /** When the entry was last used, from RenderCache::clock. */
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct RenderCache {
+    entries: BTreeMap<(String, DocId), CacheEntry>,
+    /** Counts lookups, so we can tell which entry was used least recently. */
+    clock: u64,
+}
+
+impl RenderCache {
+    fn insert(&mut self, key: (String, DocId), entry: CacheEntry) {
+        if self.entries.len() >= MAX_CACHE_ENTRIES && !self.entries.contains_key(&key) {
+            let oldest = self.entries.iter().min_by_key(|(_, e)| e.used).map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest { self.entries.remove(&oldest); }
+        }
+        self.entries.insert(key, entry);
+    }
+}
+
+/**
+ * Registered computed views. Rendered output is cached (up to MAX_CACHE_ENTRIES outputs), and the
+ * cache entry for a document is used until the document or the view's script changes.
+ */
+#[derive(Debug)]
+pub(crate) struct ComputedViews {
+    defs: BTreeMap<String, ViewDef>,
+    engine: Engine,
+    cache: Mutex<RenderCache>,
+}
+
+impl Default for ComputedViews {
+    fn default() -> Self {
+        // Scripts are sandboxed. Rhai scripts can't touch the filesystem or network, and these
+        // limits stop them running forever or eating all our memory.
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_call_levels(MAX_CALL_LEVELS);
+        engine.set_max_string_size(MAX_STRING_SIZE);
+        engine.set_max_array_size(MAX_COLLECTION_SIZE);
+        engine.set_max_map_size(MAX_COLLECTION_SIZE);
+        engine.disable_symbol("eval");
+        engine.on_print(|_| {});
+        engine.on_debug(|_, _, _| {});
+
+        ComputedViews { defs: BTreeMap::new(), engine, cache: Mutex::new(RenderCache::default()) }
+    }
+}
+
+fn heads(view: &ViewDb, key: &DocId) -> Vec<Order> {
+    view.get_cloned(key).iter().map(|v| v.order).collect()
+}
+
+impl ComputedViews {
+    pub(crate) fn register(&mut self, name: &str, def: ViewDef) {
+        self.defs.insert(name.to_string(), def);
+        self.cache.lock().unwrap().entries.retain(|(view, _), _| view != name);
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&ViewDef> {
+        self.defs.get(name)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item=(&String, &ViewDef)> {
+        self.defs.iter()
+    }
+
+    /** Check if a change to this document affects the named view of source. */
+    pub(crate) fn depends_on(&self, name: &str, source: &DocId, changed: &DocId) -> bool {
+        changed == source || self.defs.get(name).is_some_and(|def| &def.script == changed)
+    }
+
+    /** Render the named view of a document. Returns None if there's no view with that name. */
+    pub(crate) fn render(&self, ops: &OpDb, view: &ViewDb, name: &str, key: &DocId) -> Option<Result<Rendered, String>> {
+        let def = self.defs.get(name)?;
+        let mut versions = heads(view, key);
+        versions.extend(heads(view, &def.script));
+
+        let cache_key = (name.to_string(), key.clone());
+        let mut cache = self.cache.lock().unwrap();
+        cache.clock += 1;
+        let used = cache.clock;
+        if let Some(entry) = cache.entries.get_mut(&cache_key) {
+            if entry.versions == versions {
+                entry.used = used;
+                return Some(entry.output.clone());
+            }
+        }
+
+        let output = self.run(ops, view, def, key);
+        cache.insert(cache_key, CacheEntry { versions, output: output.clone(), used });
+        Some(output)
+    }
+
+    fn run(&self, ops: &OpDb, view: &ViewDb, def: &ViewDef, key: &DocId) -> Result<Rendered, String> {
+        let script = match view.merged_value(&def.script)? {
+            DocValue::Text(text) => text.to_string(),
+            DocValue::Blob { data, .. } => String::from_utf8(data)
+                .map_err(|_| format!("Script {} is not valid UTF-8", def.script))?,
+            DocValue::Json(serde_json::Value::String(s)) => s,
+            _ => return Err(format!("Script {} is not a text document", def.script)),
+        };
+
+        let doc = links::resolve(ops, view, &Link { key: key.clone(), version: None }, 0, &mut Visited::default());
+        let mut scope = Scope::new();
+        scope.push_constant("doc", rhai::serde::to_dynamic(&doc).map_err(|e| e.to_string())?);
+        scope.push_constant("key", key.clone());
+
+        let result: Dynamic = self.engine.eval_with_scope(&mut scope, &script)
+            .map_err(|e| format!("Error in script {}: {}", def.script, e))?;
+
+        if result.is_string() {
+            Ok(Rendered {
+                content_type: def.content_type.clone(),
+                body: result.into_string().unwrap().into_bytes(),
+            })
+        } else {
+            let json: serde_json::Value = rhai::serde::from_dynamic(&result).map_err(|e| e.to_string())?;
+            Ok(Rendered {
+                content_type: "application/json".to_string(),
+                body: serde_json::to_vec(&json).unwrap(),
+            })
+        }
+    }
+}
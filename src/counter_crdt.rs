@@ -0,0 +1,137 @@
+use crate::types::*;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use serde::{Serialize, Deserialize};
+
+/**
+ * A PN-counter. Each agent's increments and decrements are counted separately, and the value is
+ * the difference of the totals. Agents' totals only grow, so replicas merge by taking the larger
+ * total for each agent.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counter {
+    /** agent => (total increments, total decrements) */
+    totals: BTreeMap<String, (u64, u64)>,
+}
+
+impl Counter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn exact_value(&self) -> i128 {
+        self.totals.values()
+            .map(|(inc, dec)| *inc as i128 - *dec as i128)
+            .sum()
+    }
+
+    /**
+     * The counter's value. Writes can't take it out of the range of an i64, but concurrent writes
+     * can, so out of range values are clamped.
+     */
+    pub fn value(&self) -> i64 {
+        self.exact_value().clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+
+    /** Merge another replica's state into this one. */
+    pub fn merge(&mut self, other: &Counter) {
+        for (agent, (inc, dec)) in other.totals.iter() {
+            let entry = self.totals.entry(agent.clone()).or_default();
+            entry.0 = entry.0.max(*inc);
+            entry.1 = entry.1.max(*dec);
+        }
+    }
+
+    /**
+     * Apply an increment (or decrement, if negative) written by the operation with this version.
+     * This fails if the counter would overflow.
+     */
+    pub fn apply(&mut self, amount: i64, version: &RemoteVersion) -> Result<(), String> {
+        let (inc, dec) = self.totals.get(&version.agent).copied().unwrap_or_default();
+        let total = if amount >= 0 {
+            inc.checked_add(amount as u64).map(|inc| (inc, dec))
+        } else {
+            dec.checked_add(amount.unsigned_abs()).map(|dec| (inc, dec))
+        };
+        let value = self.exact_value() + amount as i128;
+
+        match total {
+            Some(total) if i64::try_from(value).is_ok() => {
+                self.totals.insert(version.agent.clone(), total);
+                Ok(())
+            },
+            _ => Err("Counter overflow".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(agent: &str, seq: Seq) -> RemoteVersion {
+        RemoteVersion { agent: agent.to_string(), seq }
+    }
+
+    fn applied(counter: &Counter, amount: i64, version: &RemoteVersion) -> Counter {
+        let mut counter = counter.clone();
+        counter.apply(amount, version).unwrap();
+        counter
+    }
+
+    /** Merge two replicas in both orders, check they agree, and return the merged counter. */
+    fn converge(a: &Counter, b: &Counter) -> Counter {
+        let mut ab = a.clone();
+        ab.merge(b);
+        let mut ba = b.clone();
+        ba.merge(a);
+        assert_eq!(ab, ba);
+        ab
+    }
+
+    #[test]
+    fn concurrent_writes_converge() {
+        let base = applied(&Counter::new(), 10, &version("a", 0));
+        let b = applied(&applied(&base, 5, &version("b", 0)), -2, &version("b", 1));
+        let c = applied(&base, -20, &version("c", 0));
+        assert_eq!(converge(&b, &c).value(), -7);
+
+        // Ops from different agents commute, so applying them one after another gives the same.
+        let bc = applied(&b, -20, &version("c", 0));
+        let cb = applied(&applied(&c, 5, &version("b", 0)), -2, &version("b", 1));
+        assert_eq!(bc.value(), cb.value());
+        // Merging is idempotent, and merging an older state changes nothing.
+        assert_eq!(converge(&b, &b), b);
+        assert_eq!(converge(&b, &base), b);
+    }
+
+    #[test]
+    fn concurrent_overflow_converges() {
+        let a = applied(&Counter::new(), i64::MAX, &version("a", 0));
+        let b = applied(&Counter::new(), 1, &version("b", 0));
+        // Each write is fine on its own, but not one after the other.
+        assert!(a.clone().apply(1, &version("b", 0)).is_err());
+
+        let merged = converge(&a, &b);
+        assert_eq!(merged.exact_value(), i64::MAX as i128 + 1);
+        assert_eq!(merged.value(), i64::MAX);
+        assert!(merged.clone().apply(1, &version("a", 1)).is_err());
+        assert_eq!(applied(&merged, -2, &version("a", 1)).value(), i64::MAX - 1);
+
+        let low = converge(&applied(&Counter::new(), i64::MIN, &version("a", 0)), &applied(&Counter::new(), -1, &version("b", 0)));
+        assert_eq!(low.value(), i64::MIN);
+    }
+
+    #[test]
+    fn agent_totals_overflow() {
+        // The value stays in range, but the agent's total increments don't fit in a u64.
+        let mut counter = Counter::new();
+        for seq in 0..2 {
+            counter.apply(i64::MAX, &version("a", seq * 2)).unwrap();
+            counter.apply(-i64::MAX, &version("a", seq * 2 + 1)).unwrap();
+        }
+        assert_eq!(counter.value(), 0);
+        assert!(counter.apply(i64::MAX, &version("a", 4)).is_err());
+        assert_eq!(counter.value(), 0);
+    }
+}
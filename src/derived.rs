@@ -0,0 +1,267 @@
+use crate::types::*;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+
+/**
+ * Derivations are map/reduce style. The mapper turns an input document into (output name, value)
+ * contributions. Each output keeps running totals of its contributions, and the finisher turns
+ * those into the output's value.
+ */
+pub type Mapper = fn(key: &DocId, doc: &Value) -> Vec<(String, Value)>;
+pub type Finisher = fn(totals: &Totals) -> Value;
+
+/** Derivers are registered here by name. */
+const DERIVERS: [(&str, Mapper, Finisher); 2] = [
+    ("tag-counts", tag_counts, sum),
+    ("user-totals", user_totals, count_and_sum),
+];
+
+pub fn deriver(name: &str) -> Option<(Mapper, Finisher)> {
+    DERIVERS.iter().find(|(n, _, _)| *n == name).map(|(_, m, f)| (*m, *f))
+}
+
+/** Counts documents per tag. Each string in the document's `tags` list counts once. */
+fn tag_counts(_key: &DocId, doc: &Value) -> Vec<(String, Value)> {
+    let tags: BTreeSet<&str> = doc.get("tags").and_then(|t| t.as_array()).into_iter()
+        .flatten()
+        .filter_map(|t| t.as_str())
+        .collect();
+    tags.into_iter().map(|tag| (tag.to_string(), Value::from(1))).collect()
+}
+
+/** Groups documents by their `user` field, and adds up their `amount` fields. */
+fn user_totals(_key: &DocId, doc: &Value) -> Vec<(String, Value)> {
+    let amount = doc.get("amount").filter(|a| a.is_number()).cloned().unwrap_or_else(|| Value::from(0));
+    match doc.get("user").and_then(|u| u.as_str()) {
+        Some(user) => vec!((user.to_string(), amount)),
+        None => vec!(),
+    }
+}
+
+/**
+ * Running totals of the contributions to one output. Contributions are added and removed one at a
+ * time, so an input changing only costs as much as its own contributions. Integers are summed
+ * exactly. Anything else is summed as a float (non-numbers count as 0), and that sum is reset once
+ * the last such contribution is removed, so rounding errors don't build up forever.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Totals {
+    count: u64,
+    ints: i128,
+    /** The number of contributions which aren't integers. */
+    others: u64,
+    floats: f64,
+}
+
+impl Totals {
+    fn add(&mut self, value: &Value) {
+        self.count += 1;
+        match value.as_i64() {
+            Some(i) => self.ints += i as i128,
+            None => {
+                self.others += 1;
+                self.floats += value.as_f64().unwrap_or(0.0);
+            },
+        }
+    }
+
+    fn remove(&mut self, value: &Value) {
+        self.count -= 1;
+        match value.as_i64() {
+            Some(i) => self.ints -= i as i128,
+            None => {
+                self.others -= 1;
+                self.floats -= value.as_f64().unwrap_or(0.0);
+                if self.others == 0 { self.floats = 0.0; }
+            },
+        }
+    }
+}
+
+fn sum(totals: &Totals) -> Value {
+    if totals.others == 0 {
+        i64::try_from(totals.ints).map_or_else(|_| Value::from(totals.ints as f64), Value::from)
+    } else {
+        Value::from(totals.ints as f64 + totals.floats)
+    }
+}
+
+fn count_and_sum(totals: &Totals) -> Value {
+    serde_json::json!({ "count": totals.count, "total": sum(totals) })
+}
+
+#[derive(Debug)]
+struct Derivation {
+    deriver: String,
+    prefix: String,
+    output: String,
+    map: Mapper,
+    finish: Finisher,
+    /** The (output key, value) contributions each input currently makes, so they can be removed. */
+    contributions: BTreeMap<DocId, Vec<(DocId, Value)>>,
+    totals: BTreeMap<DocId, Totals>,
+}
+
+/** A derived document's value, and the order of the operation which last changed it. */
+pub type Output = (Value, Order);
+
+/**
+ * Materialized derived documents. Each derivation maps documents with keys starting with its
+ * prefix into read-only output documents with keys starting with its output prefix. Outputs are
+ * updated incrementally. When an input changes, its old contributions are removed from the totals
+ * of the outputs it touched and its new ones added, without looking at any other input. Like
+ * indexes, documents with conflicting versions contribute all their versions.
+ */
+#[derive(Debug, Default)]
+pub struct Derivations {
+    defs: BTreeMap<String, Derivation>,
+    outputs: BTreeMap<DocId, Output>,
+}
+
+/** A change to a derived document. None means the document doesn't exist. */
+pub type OutputChange = (DocId, Option<Output>, Option<Output>);
+
+impl Derivations {
+    /** Add a derivation. Call update with every existing input document afterwards. */
+    pub fn register(&mut self, name: &str, deriver_name: &str, prefix: &str, output: &str) -> Result<(), String> {
+        let (map, finish) = deriver(deriver_name)
+            .ok_or_else(|| format!("Unknown deriver '{}'", deriver_name))?;
+        if prefix.starts_with(output) || output.starts_with(prefix) {
+            return Err("Derivation inputs and outputs must not overlap".to_string());
+        }
+        if self.defs.contains_key(name) {
+            return Err(format!("Derivation {} already exists", name));
+        }
+        if self.defs.values().any(|d| d.output.starts_with(output) || output.starts_with(&d.output)) {
+            return Err("Derivation outputs must not overlap".to_string());
+        }
+
+        self.defs.insert(name.to_string(), Derivation {
+            deriver: deriver_name.to_string(),
+            prefix: prefix.to_string(),
+            output: output.to_string(),
+            map,
+            finish,
+            contributions: BTreeMap::new(),
+            totals: BTreeMap::new(),
+        });
+        Ok(())
+    }
+
+    /** Iterate through (name, deriver, input prefix, output prefix) for each derivation. */
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &str, &str, &str)> {
+        self.defs.iter().map(|(name, d)| (name.as_str(), d.deriver.as_str(), d.prefix.as_str(), d.output.as_str()))
+    }
+
+    /** Derived documents are read only. */
+    pub fn is_output(&self, key: &DocId) -> bool {
+        self.defs.values().any(|d| key.starts_with(&d.output))
+    }
+
+    pub fn get(&self, key: &DocId) -> Option<&Output> {
+        self.outputs.get(key)
+    }
+
+    /**
+     * Update derived documents after an input document changed. Values are the input's current
+     * versions, and order is the order of the operation which changed it. Returns the outputs
+     * which changed.
+     */
+    pub fn update(&mut self, key: &DocId, values: &[DocValue], order: Order) -> Vec<OutputChange> {
+        let mut changes = Vec::new();
+
+        for d in self.defs.values_mut().filter(|d| key.starts_with(&d.prefix)) {
+            let mut touched = BTreeSet::new();
+            for (out, value) in d.contributions.remove(key).unwrap_or_default() {
+                let totals = d.totals.get_mut(&out).unwrap();
+                totals.remove(&value);
+                if totals.count == 0 { d.totals.remove(&out); }
+                touched.insert(out);
+            }
+
+            let mut contributions = Vec::new();
+            for json in values.iter().filter_map(|v| v.json()) {
+                for (name, value) in (d.map)(key, &json) {
+                    let out = format!("{}{}", d.output, name);
+                    d.totals.entry(out.clone()).or_default().add(&value);
+                    touched.insert(out.clone());
+                    contributions.push((out, value));
+                }
+            }
+            if !contributions.is_empty() { d.contributions.insert(key.clone(), contributions); }
+
+            for out in touched {
+                let new = d.totals.get(&out).map(|totals| ((d.finish)(totals), order));
+                let old = self.outputs.get(&out).cloned();
+                if old.as_ref().map(|(v, _)| v) == new.as_ref().map(|(v, _)| v) { continue; }
+
+                match &new {
+                    Some(output) => { self.outputs.insert(out.clone(), output.clone()); },
+                    None => { self.outputs.remove(&out); },
+                }
+                changes.push((out, old, new));
+            }
+        }
+
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn update(d: &mut Derivations, key: &str, value: Value, order: Order) -> Vec<OutputChange> {
+        d.update(&key.to_string(), &[DocValue::Json(value)], order)
+    }
+
+    fn output(d: &Derivations, key: &str) -> Option<Value> {
+        d.get(&key.to_string()).map(|(value, _)| value.clone())
+    }
+
+    #[test]
+    fn totals_follow_input_changes() {
+        let mut d = Derivations::default();
+        d.register("totals", "user-totals", "orders/", "totals/").unwrap();
+
+        update(&mut d, "orders/1", json!({ "user": "a", "amount": 5 }), 0);
+        update(&mut d, "orders/2", json!({ "user": "a", "amount": 7 }), 1);
+        assert_eq!(output(&d, "totals/a"), Some(json!({ "count": 2, "total": 12 })));
+
+        // Moving an order to another user only touches those two outputs.
+        let changes = update(&mut d, "orders/1", json!({ "user": "b", "amount": 5 }), 2);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(output(&d, "totals/a"), Some(json!({ "count": 1, "total": 7 })));
+        assert_eq!(d.get(&"totals/b".to_string()), Some(&(json!({ "count": 1, "total": 5 }), 2)));
+
+        update(&mut d, "orders/2", Value::Null, 3);
+        assert_eq!(output(&d, "totals/a"), None);
+    }
+
+    #[test]
+    fn float_totals_reset() {
+        let mut d = Derivations::default();
+        d.register("totals", "user-totals", "orders/", "totals/").unwrap();
+
+        update(&mut d, "orders/1", json!({ "user": "a", "amount": 1 }), 0);
+        update(&mut d, "orders/2", json!({ "user": "a", "amount": 1e20 }), 1);
+        update(&mut d, "orders/3", json!({ "user": "a", "amount": 0.5 }), 2);
+        update(&mut d, "orders/2", json!({ "user": "a", "amount": 2 }), 3);
+        update(&mut d, "orders/3", json!({ "user": "a" }), 4);
+        // Once only integers are left, the total is exact again.
+        assert_eq!(output(&d, "totals/a"), Some(json!({ "count": 3, "total": 3 })));
+    }
+
+    #[test]
+    fn tag_counts() {
+        let mut d = Derivations::default();
+        d.register("tags", "tag-counts", "posts/", "tags/").unwrap();
+
+        update(&mut d, "posts/1", json!({ "tags": ["x", "y", "x"] }), 0);
+        update(&mut d, "posts/2", json!({ "tags": ["x"] }), 1);
+        assert_eq!(output(&d, "tags/x"), Some(json!(2)));
+        assert_eq!(output(&d, "tags/y"), Some(json!(1)));
+    }
+}
@@ -0,0 +1,59 @@
+use crate::schema::ValidationError;
+use crate::types::*;
+use std::fmt;
+
+/** Errors from applying operations to the database. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DbError {
+    /** A document value didn't match the schema registered for its key. */
+    InvalidValue(ValidationError),
+    /** A patch couldn't be applied to the document version it was written against. */
+    InvalidPatch { key: DocId, message: String },
+    /** The operation references a version we don't have. */
+    MissingParent(RemoteVersion),
+    /** The operation is from an agent with no known public key. */
+    UnknownAgent(String),
+    /** The operation's signature doesn't match its agent's public key. */
+    BadSignature(RemoteVersion),
+    /** The operation's hash doesn't match its content and history. */
+    BadHash(RemoteVersion),
+    /** We already have a different operation with the same version. */
+    Equivocation(RemoteVersion),
+    /** The document belongs to another agent. */
+    NotOwner { key: DocId, owner: String },
+    /** The operation itself is malformed. */
+    InvalidOperation(String),
+    /** The operation's history has been pruned, so the data needed isn't available. */
+    HistoryPruned(RemoteVersion),
+    /** The document is derived from other documents, and can't be written directly. */
+    ReadOnly(DocId),
+    /** The change couldn't be written to storage, so it wasn't made. */
+    Storage(String),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::InvalidValue(e) => e.fmt(f),
+            DbError::InvalidPatch { key, message } => write!(f, "Invalid patch for {}: {}", key, message),
+            DbError::MissingParent(v) => write!(f, "Missing parent version {}/{}", v.agent, v.seq),
+            DbError::UnknownAgent(agent) => write!(f, "Unknown agent {}", agent),
+            DbError::BadSignature(v) => write!(f, "Bad signature on operation {}/{}", v.agent, v.seq),
+            DbError::BadHash(v) => write!(f, "Hash of operation {}/{} doesn't match its content", v.agent, v.seq),
+            DbError::Equivocation(v) => write!(f, "Operation {}/{} differs from the one we already have", v.agent, v.seq),
+            DbError::NotOwner { key, owner } => write!(f, "{} can only be written by {}", key, owner),
+            DbError::InvalidOperation(message) => write!(f, "Invalid operation: {}", message),
+            DbError::HistoryPruned(v) => write!(f, "History at {}/{} has been pruned", v.agent, v.seq),
+            DbError::ReadOnly(key) => write!(f, "{} is a derived document and can't be written", key),
+            DbError::Storage(message) => write!(f, "Could not write to storage: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<ValidationError> for DbError {
+    fn from(e: ValidationError) -> Self {
+        DbError::InvalidValue(e)
+    }
+}
@@ -1,129 +1,1316 @@
 use crate::types::*;
-use crate::MemDb;
-use crate::readchannel::channel;
+use crate::{MemDb, doc_op_entry};
+use crate::error::DbError;
+use crate::schema::glob_matches;
+use crate::patch::{JsonPatchOp, merge_patch};
+use crate::text_crdt::TextDoc;
+use crate::json_crdt::{JsonDoc, RegisterMode};
+use crate::set_crdt::{OrSet, SetOp};
+use crate::owned::{self, OwnedDoc, Action};
+use crate::index::{IndexValue, IndexQuery};
+use crate::query::{self, QueryError};
+use crate::links::{self, Link, Visited};
+use crate::subscriptions::{Target, Sources};
+use crate::computed::{self, ViewDef};
+use crate::presence::{self, Presence};
+use crate::capability::{self, Capability, Caveat, Access};
+use crate::signing;
+use crate::merkle;
+use crate::readchannel::{unbounded_channel, IOReadChannel};
+use crate::version::{encode_versions, decode_versions};
 
 use std::sync::Arc;
+use std::borrow::Cow;
 
 use async_std::task;
-use async_std::sync::RwLock;
+use async_std::sync::{RwLock, Mutex};
 use async_std::io::BufReader;
 
 use tide::{Request, Response, StatusCode};
 use tide::http::Body;
 
-use std::time::SystemTime;
-use chrono::DateTime;
+use std::time::{Duration, Instant};
+
+const DEFAULT_LIST_LIMIT: usize = 100;
+const MAX_LIST_LIMIT: usize = 1000;
+const DEFAULT_RESOLVE_DEPTH: usize = 1;
+const MAX_RESOLVE_DEPTH: usize = 16;
+const PRESENCE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const SYNC_CONTENT_TYPE: &str = "application/msgpack";
+
+/**
+ * Shared server state. Presence doesn't touch the database, so it has its own lock and presence
+ * traffic doesn't hold up reads and writes.
+ */
+#[derive(Clone)]
+struct State {
+    db: Arc<RwLock<MemDb>>,
+    presence: Arc<Mutex<Presence>>,
+}
+
+/** Braid Merge-Types for CRDT documents */
+const TEXT_MERGE_TYPE: &str = "text";
+const JSON_MERGE_TYPE: &str = "json";
+const JSON_MV_MERGE_TYPE: &str = "json-mv";
+const COUNTER_MERGE_TYPE: &str = "counter";
+const SET_MERGE_TYPE: &str = "set";
+const MERGE_TYPES: [&str; 5] = [TEXT_MERGE_TYPE, JSON_MERGE_TYPE, JSON_MV_MERGE_TYPE, COUNTER_MERGE_TYPE, SET_MERGE_TYPE];
 
 impl DocValue {
-    fn to_bytes(&self) -> &[u8] {
+    fn content_type(&self) -> &str {
+        match self {
+            DocValue::None => "text/plain",
+            DocValue::Blob { content_type, .. } => content_type,
+            DocValue::Json(_) => "application/json",
+            DocValue::Text(_) => "text/plain; charset=utf-8",
+            DocValue::JsonCrdt(_) | DocValue::Counter(_) | DocValue::Set(_) | DocValue::Owned(_) => "application/json",
+        }
+    }
+
+    fn merge_type(&self) -> Option<&'static str> {
+        match self {
+            DocValue::Text(_) => Some(TEXT_MERGE_TYPE),
+            DocValue::JsonCrdt(doc) => Some(match doc.mode {
+                RegisterMode::LastWriterWins => JSON_MERGE_TYPE,
+                RegisterMode::MultiValue => JSON_MV_MERGE_TYPE,
+            }),
+            DocValue::Counter(_) => Some(COUNTER_MERGE_TYPE),
+            DocValue::Set(_) => Some(SET_MERGE_TYPE),
+            _ => None,
+        }
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         match self {
-            DocValue::None => "None".as_bytes(),
-            DocValue::Blob(bytes) => &bytes[..]
+            DocValue::None => Cow::Borrowed("None".as_bytes()),
+            DocValue::Blob { data, .. } => Cow::Borrowed(&data[..]),
+            DocValue::Json(json) => Cow::Owned(serde_json::to_vec(json).unwrap()),
+            DocValue::Text(text) => Cow::Owned(text.to_string().into_bytes()),
+            DocValue::JsonCrdt(_) | DocValue::Counter(_) | DocValue::Set(_) | DocValue::Owned(_) => {
+                Cow::Owned(serde_json::to_vec(&self.to_json()).unwrap())
+            },
         }
     }
 }
 
+fn is_json_mime(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap().trim();
+    essence == "application/json" || essence.ends_with("+json")
+}
+
+/** JSON request bodies are stored as structured values. Everything else is kept as a blob. */
+fn parse_doc_value(content_type: Option<&str>, data: Vec<u8>) -> tide::Result<DocValue> {
+    let content_type = content_type.unwrap_or("application/octet-stream");
+    if is_json_mime(content_type) {
+        let json = serde_json::from_slice(&data)
+            .map_err(|e| tide::Error::from_str(StatusCode::BadRequest, format!("Invalid JSON: {}", e)))?;
+        Ok(DocValue::Json(json))
+    } else {
+        Ok(DocValue::Blob { content_type: content_type.to_string(), data })
+    }
+}
+
+/** Parse a Braid style range (eg `bytes [5:10]`). Ranges are half open. */
+fn parse_range(range: &str, unit: &str) -> tide::Result<(usize, usize)> {
+    let range = range.trim();
+    range.strip_prefix(unit)
+        .map(|r| r.trim())
+        .and_then(|r| r.strip_prefix('['))
+        .and_then(|r| r.strip_suffix(']'))
+        .and_then(|r| r.split_once(':'))
+        .and_then(|(start, end)| Some((start.trim().parse::<usize>().ok()?, end.trim().parse::<usize>().ok()?)))
+        .filter(|(start, end)| start <= end)
+        .ok_or_else(|| tide::Error::from_str(StatusCode::BadRequest, format!("Invalid Content-Range '{}'", range)))
+}
+
+/**
+ * Work out which Merge-Type a write should use, if any. Once a document is a CRDT it stays one,
+ * and new documents become CRDTs if the request names a Merge-Type.
+ */
+fn merge_type(req: &Request<State>, db: &MemDb, key: &DocId) -> tide::Result<Option<&'static str>> {
+    if let Ok(Some(existing)) = db.view.merged_value(key).as_ref().map(|v| v.merge_type()) {
+        return Ok(Some(existing));
+    }
+
+    match req.header("Merge-Type") {
+        None => Ok(None),
+        Some(h) => MERGE_TYPES.iter()
+            .find(|t| **t == h.as_str().trim())
+            .map(|t| Some(*t))
+            .ok_or_else(|| tide::Error::from_str(StatusCode::BadRequest, format!("Unknown Merge-Type '{}'", h.as_str()))),
+    }
+}
+
+/**
+ * Writes to CRDT documents are converted to CRDT operations against the document's current
+ * (merged) value.
+ *
+ * Text documents are edited with positional edits (in characters). PUT requests replace the whole
+ * document content, and PATCH requests carry a `Content-Range: text [start:end]` header.
+ *
+ * JSON documents are diffed against the new value. PUT requests contain the new value, and PATCH
+ * requests contain a JSON merge patch or JSON patch.
+ *
+ * Counters are set to a new value with PUT, or incremented by the number in a PATCH request. Sets
+ * are replaced with a JSON list with PUT, or edited with `{"add": [...], "remove": [...]}` in a
+ * PATCH request.
+ */
+fn crdt_patch(req: &Request<State>, db: &MemDb, key: &DocId, merge_type: &str, data: Vec<u8>) -> tide::Result<DocPatch> {
+    let current = db.view.merged_value(key)
+        .map_err(|e| tide::Error::from_str(StatusCode::Conflict, e))?;
+    let wrong_type = || tide::Error::from_str(StatusCode::Conflict,
+        format!("Document is not a {} document", merge_type));
+    let bad_request = |msg: String| tide::Error::from_str(StatusCode::BadRequest, msg);
+    let json = |data: &[u8]| serde_json::from_slice::<serde_json::Value>(data)
+        .map_err(|e| bad_request(format!("Invalid JSON: {}", e)));
+    let is_put = req.method() == tide::http::Method::Put;
+
+    match merge_type {
+        TEXT_MERGE_TYPE => {
+            let content = String::from_utf8(data)
+                .map_err(|_| bad_request("Text must be valid UTF-8".to_string()))?;
+
+            let doc = match current {
+                DocValue::Text(doc) => doc,
+                DocValue::None => TextDoc::new(),
+                _ => return Err(wrong_type()),
+            };
+
+            let ops = match (is_put, req.header("Content-Range")) {
+                (true, _) => doc.diff_ops(&content),
+                (false, Some(range)) => {
+                    let (start, end) = parse_range(range.as_str(), "text")?;
+                    doc.edit_ops(start, end - start, &content).map_err(bad_request)?
+                },
+                (false, None) => return Err(bad_request("Text patches need a Content-Range".to_string())),
+            };
+            Ok(DocPatch::Text(ops))
+        },
+        COUNTER_MERGE_TYPE => {
+            let current = match current {
+                DocValue::Counter(counter) => counter.value(),
+                DocValue::None => 0,
+                _ => return Err(wrong_type()),
+            };
+            let n = json(&data)?.as_i64()
+                .ok_or_else(|| bad_request("Expected an integer".to_string()))?;
+            let amount = if is_put { n.checked_sub(current) } else { current.checked_add(n).map(|_| n) };
+            Ok(DocPatch::Counter(amount.ok_or_else(|| bad_request("Counter overflow".to_string()))?))
+        },
+        SET_MERGE_TYPE => {
+            let set = match current {
+                DocValue::Set(set) => set,
+                DocValue::None => OrSet::new(),
+                _ => return Err(wrong_type()),
+            };
+            let list = |v: Option<&serde_json::Value>| -> tide::Result<Vec<serde_json::Value>> {
+                match v {
+                    None => Ok(vec!()),
+                    Some(serde_json::Value::Array(items)) => Ok(items.clone()),
+                    Some(_) => Err(bad_request("Expected a list of set elements".to_string())),
+                }
+            };
+
+            let body = json(&data)?;
+            let ops = if is_put {
+                set.diff_ops(&list(Some(&body))?)
+            } else {
+                let mut ops: Vec<SetOp> = list(body.get("remove"))?.iter()
+                    .filter_map(|v| set.remove_op(v))
+                    .collect();
+                ops.extend(list(body.get("add"))?.into_iter().map(SetOp::Add));
+                ops
+            };
+            Ok(DocPatch::Set(ops))
+        },
+        _ => {
+            let mode = if merge_type == JSON_MV_MERGE_TYPE { RegisterMode::MultiValue } else { RegisterMode::LastWriterWins };
+            let doc = match current {
+                DocValue::JsonCrdt(doc) => doc,
+                DocValue::None => JsonDoc::new(mode),
+                _ => return Err(wrong_type()),
+            };
+
+            let target = if is_put {
+                json(&data)?
+            } else {
+                let mut target = doc.to_json();
+                match parse_doc_patch(req, data)? {
+                    DocPatch::MergePatch(patch) => merge_patch(&mut target, &patch),
+                    DocPatch::JsonPatch(ops) => for op in ops {
+                        op.apply(&mut target).map_err(|e| tide::Error::from_str(StatusCode::UnprocessableEntity, e))?;
+                    },
+                    _ => return Err(tide::Error::from_str(StatusCode::UnsupportedMediaType,
+                        "JSON documents can only be patched with JSON merge patches or JSON patches")),
+                }
+                target
+            };
+
+            let ops = doc.diff_ops(&target).map_err(bad_request)?;
+            Ok(DocPatch::JsonCrdt { mode: doc.mode, ops })
+        },
+    }
+}
+
+/** Owned documents can only be changed with actions (POST). */
+fn check_not_owned(db: &MemDb, key: &DocId) -> tide::Result<()> {
+    if let Ok(DocValue::Owned(_)) = db.view.merged_value(key) {
+        return Err(tide::Error::from_str(StatusCode::Conflict,
+            "Owned documents can only be changed with actions"));
+    }
+    Ok(())
+}
+
+/**
+ * Parse the body of a PATCH request. The kind of patch is named by the content type, except for
+ * byte splices which are sent with a Braid style `Content-Range: bytes [start:end]` header.
+ */
+fn parse_doc_patch(req: &Request<State>, data: Vec<u8>) -> tide::Result<DocPatch> {
+    let bad_request = |msg: String| tide::Error::from_str(StatusCode::BadRequest, msg);
+
+    if let Some(range) = req.header("Content-Range") {
+        let (start, end) = parse_range(range.as_str(), "bytes")?;
+        return Ok(DocPatch::Splice { pos: start, remove: end - start, insert: data });
+    }
+
+    let content_type = req.header("Content-Type").map(|h| h.as_str()).unwrap_or("");
+    let json = || serde_json::from_slice::<serde_json::Value>(&data)
+        .map_err(|e| bad_request(format!("Invalid JSON: {}", e)));
+
+    match content_type.split(';').next().unwrap().trim() {
+        "application/merge-patch+json" => Ok(DocPatch::MergePatch(json()?)),
+        "application/json-patch+json" => Ok(DocPatch::JsonPatch(JsonPatchOp::parse_list(&json()?)
+            .map_err(bad_request)?)),
+        _ => Err(tide::Error::from_str(StatusCode::UnsupportedMediaType,
+            "Expected a JSON merge patch, a JSON patch or a Content-Range")),
+    }
+}
+
+/** Check if the client's Accept header allows a response with the specified content type. */
+fn accepts(accept: Option<&str>, content_type: &str) -> bool {
+    let accept = match accept {
+        None => return true,
+        Some(a) => a,
+    };
+    let essence = content_type.split(';').next().unwrap().trim();
+    let (ty, _) = essence.split_at(essence.find('/').unwrap_or(essence.len()));
+
+    accept.split(',').any(|range| {
+        let mut parts = range.split(';');
+        let mime = parts.next().unwrap().trim();
+        let rejected = parts.any(|p| {
+            p.trim().strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+        });
+
+        !rejected && (mime == "*/*"
+            || mime == essence
+            || mime.strip_suffix("/*") == Some(ty))
+    })
+}
+
+/**
+ * Optimistic concurrency. Writes only go ahead if the client has seen the current version(s) of
+ * the document. Returns the response to send if the precondition fails.
+ */
+fn check_if_match(req: &Request<State>, db: &MemDb, key: &DocId) -> tide::Result<Option<Response>> {
+    if let Some(if_match) = req.header("If-Match") {
+        let current = db.doc_versions(key);
+        if !etag_matches(if_match.as_str(), &current, !db.view.is_deleted(key))? {
+            return Ok(Some(Response::builder(StatusCode::PreconditionFailed)
+                .header("ETag", etag_for(&current))
+                .header("version", current.iter()
+                    .map(|v| v.encode())
+                    .collect::<Vec<String>>()
+                    .join(", "))
+                .body("")
+                .build()));
+        }
+    }
+    Ok(None)
+}
+
+fn write_response(db: &MemDb, key: &DocId, order: Order) -> Response {
+    let version = db.op_db.order_to_remote_version(order);
+
+    Response::builder(StatusCode::Ok)
+        .header("version", version.encode())
+        .header("ETag", etag_for(&db.doc_versions(key)))
+        .body("")
+        .build()
+}
+
+fn error_response(err: &DbError) -> Response {
+    let body = match err {
+        DbError::InvalidValue(e) => serde_json::json!({
+            "error": "InvalidValue",
+            "key": e.key,
+            "path": e.path,
+            "message": e.message,
+        }),
+        DbError::InvalidPatch { key, message } => serde_json::json!({
+            "error": "InvalidPatch",
+            "key": key,
+            "message": message,
+        }),
+        DbError::MissingParent(_) => serde_json::json!({
+            "error": "MissingParent",
+            "message": err.to_string(),
+        }),
+        DbError::UnknownAgent(agent) => serde_json::json!({
+            "error": "UnknownAgent",
+            "agent": agent,
+            "message": err.to_string(),
+        }),
+        DbError::BadSignature(_) => serde_json::json!({
+            "error": "BadSignature",
+            "message": err.to_string(),
+        }),
+        DbError::BadHash(_) => serde_json::json!({
+            "error": "BadHash",
+            "message": err.to_string(),
+        }),
+        DbError::Equivocation(_) => serde_json::json!({
+            "error": "Equivocation",
+            "message": err.to_string(),
+        }),
+        DbError::NotOwner { key, owner } => serde_json::json!({
+            "error": "NotOwner",
+            "key": key,
+            "owner": owner,
+            "message": err.to_string(),
+        }),
+        DbError::InvalidOperation(_) => serde_json::json!({
+            "error": "InvalidOperation",
+            "message": err.to_string(),
+        }),
+        DbError::HistoryPruned(_) => serde_json::json!({
+            "error": "HistoryPruned",
+            "message": err.to_string(),
+        }),
+        DbError::ReadOnly(key) => serde_json::json!({
+            "error": "ReadOnly",
+            "key": key,
+            "message": err.to_string(),
+        }),
+        DbError::Storage(_) => serde_json::json!({
+            "error": "Storage",
+            "message": err.to_string(),
+        }),
+    };
+    let status = match err {
+        DbError::ReadOnly(_) | DbError::NotOwner { .. } => StatusCode::Forbidden,
+        DbError::HistoryPruned(_) => StatusCode::Gone,
+        DbError::Equivocation(_) => StatusCode::Conflict,
+        DbError::Storage(_) => StatusCode::InternalServerError,
+        _ => StatusCode::UnprocessableEntity,
+    };
+
+    Response::builder(status)
+        .body(body)
+        .build()
+}
+
+fn etag_for(versions: &[RemoteVersion]) -> String {
+    format!("\"{}\"", encode_versions(versions))
+}
+
+/**
+ * Check an If-Match / If-None-Match header against a document's current versions. The header
+ * contains a comma separated list of (optionally quoted) entity tags, or "*" to match any
+ * document which exists (deleted documents don't). Each tag is a set of versions as produced by
+ * encode_versions.
+ */
+fn etag_matches(header: &str, current: &[RemoteVersion], exists: bool) -> tide::Result<bool> {
+    let header = header.trim();
+    if header == "*" { return Ok(exists); }
+
+    for tag in header.split(',') {
+        let tag = tag.trim();
+        let tag = tag.strip_prefix("W/").unwrap_or(tag).trim_matches('"');
+
+        let mut versions = decode_versions(tag).ok_or_else(|| {
+            tide::Error::from_str(StatusCode::BadRequest, "Invalid version in entity tag")
+        })?;
+        versions.sort();
+        if versions == current { return Ok(true); }
+    }
+
+    Ok(false)
+}
+
+/**
+ * Get the capability the request carries in its `Authorization: Bearer <token>` header. Requests
+ * without a valid capability are rejected.
+ */
+fn capability(req: &Request<State>, db: &MemDb) -> tide::Result<Capability> {
+    req.header("Authorization")
+        .and_then(|h| h.as_str().trim().strip_prefix("Bearer "))
+        .and_then(|token| db.authority.verify(token))
+        .ok_or_else(|| tide::Error::from_str(StatusCode::Unauthorized, "Missing or invalid capability"))
+}
+
+fn require(cap: &Capability, access: Access, key: &str) -> tide::Result<()> {
+    if cap.allows(access, key, capability::now()) { Ok(()) } else { Err(forbidden(access, key)) }
+}
+
+fn forbidden(access: Access, key: &str) -> tide::Error {
+    tide::Error::from_str(StatusCode::Forbidden,
+        format!("Capability does not allow {} access to '{}'", access.name(), key))
+}
+
+/** Check the request's capability allows a kind of access to a key (or a key prefix). */
+fn authorize(req: &Request<State>, db: &MemDb, access: Access, key: &str) -> tide::Result<Capability> {
+    let cap = capability(req, db)?;
+    require(&cap, access, key)?;
+    Ok(cap)
+}
+
+/** The part of a key pattern before the first wildcard. Patterns are authorized as this prefix. */
+fn pattern_prefix(pattern: &str) -> &str {
+    &pattern[..pattern.find('*').unwrap_or(pattern.len())]
+}
+
+fn query_param(req: &Request<State>, name: &str) -> Option<String> {
+    req.url().query_pairs().find(|(k, _)| k == name).map(|(_, v)| v.to_string())
+}
+
+/**
+ * Subscribe to a target, responding with a stream of updates. The stream starts with the current
+ * state of the target. The stream ends once the capability no longer allows the subscription.
+ */
+fn subscribe(db: &mut MemDb, target: Target, cap: Capability) -> Response {
+    let (sender, reader) = unbounded_channel();
+    let version = encode_versions(&db.branch_versions());
+    let content_type = match &target {
+        Target::View { name, .. } => db.computed.get(name)
+            .map_or(computed::DEFAULT_CONTENT_TYPE, |def| def.content_type.as_str())
+            .to_string(),
+        _ => "application/json".to_string(),
+    };
+    let sources = Sources { ops: &db.op_db, view: &db.view, computed: &db.computed };
+    if !db.subscriptions.add(&sources, &version, target, cap, sender) {
+        return Response::new(StatusCode::Forbidden);
+    }
+    stream_response(reader, &content_type)
+}
+
+/** A response which streams update frames (see subscriptions::frame) until the client leaves. */
+fn stream_response(reader: IOReadChannel, content_type: &str) -> Response {
+    let mut res = Response::new(StatusCode::Ok);
+    res.insert_header("subscribe", "keep-alive");
+    res.insert_header("cache-control", "no-cache");
+    res.insert_header("content-type", content_type);
+    res.set_body(Body::from_reader(BufReader::new(reader), None));
+    res
+}
 
 pub async fn host(db: MemDb) -> std::io::Result<()> {
-    type State = Arc<RwLock<MemDb>>;
-    let state = Arc::new(RwLock::new(db));
+    let state = State {
+        db: Arc::new(RwLock::new(db)),
+        presence: Arc::new(Mutex::new(Presence::default())),
+    };
+
+    // Expire presence entries, and clean up after clients which have disconnected.
+    let sweeper = state.presence.clone();
+    task::spawn(async move {
+        loop {
+            task::sleep(PRESENCE_SWEEP_INTERVAL).await;
+            sweeper.lock().await.sweep(Instant::now());
+        }
+    });
 
     let mut app = tide::with_state(state);
-    app.at("/doc/:key").get(|req: Request<State>| async move {
-        let key = req.param("key")?;
-        let doc = req.state().read().await.view.get_cloned(&key.to_string());
+    app.at("/doc/*key").get(|req: Request<State>| async move {
+        let key = req.param("key")?.to_string();
+        let state = req.state().db.read().await;
+        authorize(&req, &state, Access::Read, &key)?;
+        let doc = state.view.get_resolved(&key);
+        let versions = state.doc_versions(&key);
+        let etag = etag_for(&versions);
         // println!("doc {:?}", doc);
 
-        if doc.len() == 1 {
-            Ok(Response::builder(StatusCode::Ok)
-                .content_type("text/plain")
-                .body(doc[0].value.to_bytes())
+        if let Some(if_none_match) = req.header("If-None-Match") {
+            if etag_matches(if_none_match.as_str(), &versions, !state.view.is_deleted(&key))? {
+                return Ok(Response::builder(StatusCode::NotModified)
+                    .header("ETag", etag)
+                    .build());
+            }
+        }
+
+        if doc.iter().all(|v| v.value == DocValue::None) {
+            // The document doesn't exist or has been deleted.
+            Ok(Response::builder(StatusCode::NotFound)
+                .header("ETag", etag)
                 .build())
+        } else if doc.len() == 1 {
+            let value = &doc[0].value;
+            let accept = req.header("Accept").map(|h| h.as_str());
+            if !accepts(accept, value.content_type()) {
+                return Ok(Response::builder(StatusCode::NotAcceptable)
+                    .header("ETag", etag)
+                    .body(format!("Document is stored as {}", value.content_type()))
+                    .build());
+            }
+
+            let mut res = Response::builder(StatusCode::Ok)
+                .content_type(value.content_type())
+                .header("ETag", etag)
+                .body(&*value.to_bytes())
+                .build();
+            if let Some(merge_type) = value.merge_type() {
+                res.insert_header("Merge-Type", merge_type);
+            }
+            Ok(res)
         } else {
             Ok(Response::from("waaah"))
         }
     });
 
-    app.at("/doc/:key").put(|mut req: Request<State>| async move {
+    app.at("/doc/*key").put(|mut req: Request<State>| async move {
         let content = req.body_bytes().await?;
-        let key = req.param("key")?;
-
-        // We're stuck using agent 0.
-        let mut state = req.state().write().await;
-        let succeeds = state.op_db.max_seq(0);
-        let seq = match succeeds {
-            None => 0,
-            Some(i) => i + 1
+        let key = req.param("key")?.to_string();
+        let mut state = req.state().db.write().await;
+        authorize(&req, &state, Access::Write, &key)?;
+
+        if let Some(res) = check_if_match(&req, &state, &key)? { return Ok(res); }
+        check_not_owned(&state, &key)?;
+
+        let patch = if let Some(reducer) = req.header("Reducer") {
+            // Create an owned document. We're the owner, and the body is the initial state.
+            let reducer = reducer.as_str().trim().to_string();
+            if owned::reducer(&reducer).is_none() {
+                return Err(tide::Error::from_str(StatusCode::BadRequest, format!("Unknown reducer '{}'", reducer)));
+            }
+            let initial = serde_json::from_slice(&content)
+                .map_err(|e| tide::Error::from_str(StatusCode::BadRequest, format!("Invalid JSON: {}", e)))?;
+            DocPatch::Replace(DocValue::Owned(OwnedDoc::new(state.agent.clone(), reducer, initial)))
+        } else {
+            match merge_type(&req, &state, &key)? {
+                Some(merge_type) => crdt_patch(&req, &state, &key, merge_type, content)?,
+                None => DocPatch::Replace(parse_doc_value(req.header("Content-Type").map(|h| h.as_str()), content)?),
+            }
+        };
+
+        match state.write_local(&key, patch) {
+            Ok(order) => Ok(write_response(&state, &key, order)),
+            Err(e) => Ok(error_response(&e)),
+        }
+    });
+
+    app.at("/doc/*key").patch(|mut req: Request<State>| async move {
+        let content = req.body_bytes().await?;
+        let key = req.param("key")?.to_string();
+        let mut state = req.state().db.write().await;
+        authorize(&req, &state, Access::Write, &key)?;
+
+        if let Some(res) = check_if_match(&req, &state, &key)? { return Ok(res); }
+        check_not_owned(&state, &key)?;
+
+        let patch = match merge_type(&req, &state, &key)? {
+            Some(merge_type) => crdt_patch(&req, &state, &key, merge_type, content)?,
+            None => parse_doc_patch(&req, content)?,
+        };
+
+        match state.write_local(&key, patch) {
+            Ok(order) => Ok(write_response(&state, &key, order)),
+            Err(e) => Ok(error_response(&e)),
+        }
+    });
+
+    // Actions on owned documents. The body is {"action": name, "args": ...}. Actions are applied
+    // immediately if we own the document. Otherwise they're proposals, and we respond with 202.
+    app.at("/doc/*key").post(|mut req: Request<State>| async move {
+        let body: serde_json::Value = req.body_json().await?;
+        let key = req.param("key")?.to_string();
+        let mut state = req.state().db.write().await;
+        authorize(&req, &state, Access::Write, &key)?;
+
+        let owner = match state.view.merged_value(&key) {
+            Ok(DocValue::Owned(doc)) => doc.owner,
+            _ => return Err(tide::Error::from_str(StatusCode::Conflict, "Document is not an owned document")),
+        };
+        let action = Action {
+            name: body.get("action").and_then(|a| a.as_str())
+                .ok_or_else(|| tide::Error::from_str(StatusCode::BadRequest, "Missing action name"))?
+                .to_string(),
+            args: body.get("args").cloned().unwrap_or(serde_json::Value::Null),
         };
-        let doc_succeeds: Vec<RemoteVersion> = state.view.get_cloned(&key.to_string())
-            .iter()
-            .map(|v| v.order)
-            .map(|order| state.op_db.order_to_remote_version(order))
+
+        match state.write_local(&key, DocPatch::Action(action)) {
+            Ok(order) => {
+                let mut res = write_response(&state, &key, order);
+                if owner != state.agent { res.set_status(StatusCode::Accepted); }
+                Ok(res)
+            },
+            Err(e) => Ok(error_response(&e)),
+        }
+    });
+
+    app.at("/doc/*key").delete(|req: Request<State>| async move {
+        let key = req.param("key")?.to_string();
+        let mut state = req.state().db.write().await;
+        authorize(&req, &state, Access::Write, &key)?;
+
+        if let Some(res) = check_if_match(&req, &state, &key)? { return Ok(res); }
+
+        // Deleting writes a tombstone. The document's history is kept.
+        match state.write_local(&key, DocPatch::Replace(DocValue::None)) {
+            Ok(order) => Ok(write_response(&state, &key, order)),
+            Err(e) => Ok(error_response(&e)),
+        }
+    });
+
+    // List documents by key prefix. With a Subscribe header, the response is the current listing
+    // followed by a stream of add, update and remove events as documents enter and leave it.
+    app.at("/docs").get(|req: Request<State>| async move {
+        let mut prefix = String::new();
+        let mut after = None;
+        let mut limit = DEFAULT_LIST_LIMIT;
+        let mut include_values = false;
+
+        for (k, v) in req.url().query_pairs() {
+            match k.as_ref() {
+                "prefix" => prefix = v.to_string(),
+                "after" => after = Some(v.to_string()),
+                "limit" => limit = v.parse::<usize>()
+                    .map_err(|_| tide::Error::from_str(StatusCode::BadRequest, "Invalid limit"))?
+                    .min(MAX_LIST_LIMIT),
+                "values" => include_values = v != "false" && v != "0",
+                _ => {}
+            }
+        }
+
+        if req.header("Subscribe").is_some() {
+            let mut state = req.state().db.write().await;
+            let cap = authorize(&req, &state, Access::Subscribe, &prefix)?;
+            return Ok(subscribe(&mut state, Target::Prefix { prefix }, cap));
+        }
+
+        let state = req.state().db.read().await;
+        authorize(&req, &state, Access::Read, &prefix)?;
+        // Fetch one extra item so we know if there's another page.
+        let mut docs: Vec<(&DocId, DbValue)> = state.view.list(&prefix, after.as_ref())
+            .take(limit + 1)
             .collect();
-        let parents: Vec<RemoteVersion> = state.view.branch.iter()
-            .map(|order| state.op_db.order_to_version(*order))
-            .map(|local| local.to_remote(&state.op_db.agent_map))
+        let more = docs.len() > limit;
+        docs.truncate(limit);
+
+        let next = if more { docs.last().map(|(key, _)| key.to_string()) } else { None };
+        let docs: Vec<serde_json::Value> = docs.into_iter().map(|(key, vals)| {
+            let mut entry = serde_json::json!({
+                "key": key,
+                "versions": state.doc_versions(key).iter()
+                    .map(|v| v.encode())
+                    .collect::<Vec<String>>(),
+            });
+            if include_values {
+                entry["values"] = vals.iter().map(|v| v.value.to_json()).collect();
+            }
+            entry
+        }).collect();
+
+        Ok(Response::builder(StatusCode::Ok)
+            .body(serde_json::json!({
+                "docs": docs,
+                "next": next,
+            }))
+            .build())
+    });
+
+    app.at("/schemas").get(|req: Request<State>| async move {
+        let state = req.state().db.read().await;
+        let cap = capability(&req, &state)?;
+        let now = capability::now();
+        let schemas: Vec<serde_json::Value> = state.schemas.iter()
+            .filter(|(pattern, _, _)| cap.allows(Access::Read, pattern_prefix(pattern), now))
+            .map(|(pattern, version, schema)| serde_json::json!({
+                "pattern": pattern,
+                "version": version,
+                "schema": schema,
+            }))
             .collect();
 
-        let agent = "seph".to_string();
-        let op = RemoteOperation {
-            version: RemoteVersion { agent, seq },
-            succeeds,
-            parents,
-            doc_ops: vec!(RemoteDocOp {
-                id: key.to_string(),
-                patch: DocValue::Blob(content),
-                parents: doc_succeeds
-            })
+        Ok(Response::builder(StatusCode::Ok)
+            .body(serde_json::Value::Array(schemas))
+            .build())
+    });
+
+    app.at("/schema/*pattern").get(|req: Request<State>| async move {
+        let pattern = req.param("pattern")?;
+        let state = req.state().db.read().await;
+        authorize(&req, &state, Access::Read, pattern_prefix(pattern))?;
+
+        Ok(match state.schemas.get(pattern) {
+            Some((version, schema)) => Response::builder(StatusCode::Ok)
+                .header("version", version.to_string())
+                .body(schema.clone())
+                .build(),
+            None => Response::new(StatusCode::NotFound)
+        })
+    });
+
+    app.at("/schema/*pattern").put(|mut req: Request<State>| async move {
+        let schema: serde_json::Value = req.body_json().await?;
+        let pattern = req.param("pattern")?.to_string();
+        let mut state = req.state().db.write().await;
+        authorize(&req, &state, Access::Write, pattern_prefix(&pattern))?;
+
+        let version = state.schemas.register(&pattern, schema)
+            .map_err(|e| tide::Error::from_str(StatusCode::BadRequest, e))?;
+
+        // Existing documents aren't changed when a schema evolves, but let the caller know about
+        // any which no longer validate.
+        let nonconforming: Vec<&DocId> = state.view.list("", None)
+            .filter(|(key, _)| glob_matches(&pattern, key))
+            .filter(|(key, vals)| vals.iter().any(|v| state.schemas.validate(key, &v.value).is_err()))
+            .map(|(key, _)| key)
+            .collect();
+
+        Ok(Response::builder(StatusCode::Ok)
+            .header("version", version.to_string())
+            .body(serde_json::json!({
+                "pattern": pattern,
+                "version": version,
+                "nonconforming": nonconforming,
+            }))
+            .build())
+    });
+
+    // Queries are evaluated while holding the read lock, so the whole response comes from one
+    // version of the database. That version is returned so clients can subscribe from it.
+    app.at("/query").post(|mut req: Request<State>| async move {
+        let query: serde_json::Value = req.body_json().await?;
+        let state = req.state().db.read().await;
+        let cap = capability(&req, &state)?;
+
+        let readable = |key: &DocId| cap.allows(Access::Read, key, capability::now());
+        let data = query::evaluate(&state.op_db, &state.view, &query, &readable)
+            .map_err(|e| match e {
+                QueryError::Invalid(message) => tide::Error::from_str(StatusCode::BadRequest, message),
+                QueryError::Unreadable(key) => forbidden(Access::Read, &key),
+            })?;
+        let version = encode_versions(&state.branch_versions());
+
+        Ok(Response::builder(StatusCode::Ok)
+            .header("version", version.as_str())
+            .body(serde_json::json!({
+                "data": data,
+                "version": version,
+            }))
+            .build())
+    });
+
+    // Get a document with links transcluded, up to ?depth= levels deep. With a Subscribe header
+    // the response stays open and a new snapshot is sent whenever any transcluded document changes.
+    app.at("/resolve/*key").get(|req: Request<State>| async move {
+        let key = req.param("key")?.to_string();
+        let mut depth = DEFAULT_RESOLVE_DEPTH;
+        let mut version = None;
+        for (k, v) in req.url().query_pairs() {
+            match k.as_ref() {
+                "depth" => depth = v.parse::<usize>()
+                    .map_err(|_| tide::Error::from_str(StatusCode::BadRequest, "Invalid depth"))?
+                    .min(MAX_RESOLVE_DEPTH),
+                "version" => version = Some(decode_versions(&v)
+                    .ok_or_else(|| tide::Error::from_str(StatusCode::BadRequest, "Invalid version"))?),
+                _ => {}
+            }
+        }
+        let link = Link { key, version };
+
+        if req.header("Subscribe").is_none() {
+            let state = req.state().db.read().await;
+            let cap = authorize(&req, &state, Access::Read, &link.key)?;
+            let mut visited = Visited::default();
+            let value = links::resolve(&state.op_db, &state.view, &link, depth, &mut visited);
+            for key in visited.touched.iter() { require(&cap, Access::Read, key)?; }
+            return Ok(Response::builder(StatusCode::Ok)
+                .header("version", encode_versions(&state.branch_versions()).as_str())
+                .body(value)
+                .build());
+        }
+
+        let mut state = req.state().db.write().await;
+        let cap = authorize(&req, &state, Access::Subscribe, &link.key)?;
+        Ok(subscribe(&mut state, Target::Resolve { link, depth, visited: Visited::default() }, cap))
+    });
+
+    app.at("/backlinks/*key").get(|req: Request<State>| async move {
+        let key = req.param("key")?.to_string();
+        let state = req.state().db.read().await;
+        let cap = authorize(&req, &state, Access::Read, &key)?;
+        // Only list the documents the caller could read anyway.
+        let now = capability::now();
+        let sources: Vec<&DocId> = state.view.backlinks.get(&key)
+            .filter(|source| cap.allows(Access::Read, source, now))
+            .collect();
+
+        Ok(Response::builder(StatusCode::Ok)
+            .body(serde_json::json!(sources))
+            .build())
+    });
+
+    // Presence. Clients are identified by their capability (see presence::Presence), so clients
+    // which need separate entries need separate (eg attenuated) tokens. Entries expire after ?ttl=
+    // seconds unless they're set again. GET with a Subscribe header watches the room, and the
+    // watcher's entry is removed when the connection drops.
+    app.at("/presence/*room").get(|req: Request<State>| async move {
+        let room = req.param("room")?.to_string();
+
+        if req.header("Subscribe").is_none() {
+            authorize(&req, &*req.state().db.read().await, Access::Read, &room)?;
+            return Ok(Response::builder(StatusCode::Ok)
+                .body(serde_json::json!({ "clients": req.state().presence.lock().await.get(&room) }))
+                .build());
+        }
+
+        let cap = authorize(&req, &*req.state().db.read().await, Access::Subscribe, &room)?;
+        let (sender, reader) = unbounded_channel();
+        req.state().presence.lock().await.watch(&room, cap, sender);
+        Ok(stream_response(reader, "application/json"))
+    });
+
+    app.at("/presence/*room").put(|mut req: Request<State>| async move {
+        let content = req.body_bytes().await?;
+        let room = req.param("room")?.to_string();
+        let ttl = match query_param(&req, "ttl") {
+            Some(ttl) => Duration::from_secs(ttl.parse::<u64>()
+                .map_err(|_| tide::Error::from_str(StatusCode::BadRequest, "Invalid ttl"))?),
+            None => presence::DEFAULT_TTL,
         };
 
-        let order = state.apply_and_advance(&op);
-        let version = state.op_db.order_to_remote_version(order);
+        if content.len() > presence::MAX_STATE_SIZE {
+            return Ok(Response::new(StatusCode::PayloadTooLarge));
+        }
+        let value = serde_json::from_slice(&content)
+            .map_err(|e| tide::Error::from_str(StatusCode::BadRequest, format!("Invalid JSON: {}", e)))?;
+
+        let cap = authorize(&req, &*req.state().db.read().await, Access::Write, &room)?;
+        req.state().presence.lock().await.set(&room, &cap.holder(), value, ttl, Instant::now());
+        Ok(Response::new(StatusCode::Ok))
+    });
+
+    app.at("/presence/*room").delete(|req: Request<State>| async move {
+        let room = req.param("room")?.to_string();
+
+        let cap = authorize(&req, &*req.state().db.read().await, Access::Write, &room)?;
+        let removed = req.state().presence.lock().await.remove(&room, &cap.holder());
+        Ok(Response::new(if removed { StatusCode::Ok } else { StatusCode::NotFound }))
+    });
+
+    app.at("/views").get(|req: Request<State>| async move {
+        let state = req.state().db.read().await;
+        let cap = capability(&req, &state)?;
+        let now = capability::now();
+        let views: Vec<serde_json::Value> = state.computed.iter()
+            .filter(|(_, def)| cap.allows(Access::Read, &def.script, now))
+            .map(|(name, def)| serde_json::json!({
+                "name": name,
+                "script": def.script,
+                "content_type": def.content_type,
+            }))
+            .collect();
 
         Ok(Response::builder(StatusCode::Ok)
-            .header("version", version.encode())
-            .body("")
+            .body(serde_json::Value::Array(views))
             .build())
     });
 
-    app.at("/test").get(|_| async move {
-        let mut res = Response::new(StatusCode::Ok);
-        res.insert_header("Subscribe", "keep-alive");
+    // Register a computed view. The body is {"script": key of the script document,
+    // "content_type": content type of string outputs (optional)}.
+    app.at("/view/:name").put(|mut req: Request<State>| async move {
+        let body: serde_json::Value = req.body_json().await?;
+        let name = req.param("name")?.to_string();
+        let script = body.get("script").and_then(|s| s.as_str())
+            .ok_or_else(|| tide::Error::from_str(StatusCode::BadRequest, "Missing 'script'"))?
+            .to_string();
+        let content_type = body.get("content_type").and_then(|s| s.as_str())
+            .unwrap_or(computed::DEFAULT_CONTENT_TYPE)
+            .to_string();
 
-        let (sender, reader) = channel();
+        // View names are global, so (re)defining a view needs write access to everything.
+        let mut state = req.state().db.write().await;
+        authorize(&req, &state, Access::Write, "")?;
+        state.register_view(&name, ViewDef { script, content_type });
+        Ok(Response::new(StatusCode::Ok))
+    });
 
-        task::spawn(async move {
-            loop {
-                let now = SystemTime::now();
-                let datetime: DateTime<chrono::Utc> = DateTime::from(now);
-                let date_str = datetime.to_rfc2822();
+    // Render a document with a computed view. With a Subscribe header, the output is sent again
+    // whenever it changes.
+    app.at("/view/:name/*key").get(|req: Request<State>| async move {
+        let name = req.param("name")?.to_string();
+        let key = req.param("key")?.to_string();
 
-                let bytes = date_str.as_bytes();
-                let content = format!("content-length: {}\r\n\r\n", bytes.len());
-                sender.send(content.into_bytes()).await.unwrap();
-                sender.send(bytes.to_vec()).await.unwrap();
-                async_std::task::sleep(std::time::Duration::from_secs(1)).await;
+        if req.header("Subscribe").is_some() {
+            let mut state = req.state().db.write().await;
+            let cap = authorize(&req, &state, Access::Subscribe, &key)?;
+            if state.computed.get(&name).is_none() {
+                return Ok(Response::new(StatusCode::NotFound));
             }
-        });
+            return Ok(subscribe(&mut state, Target::View { name, key }, cap));
+        }
+
+        let state = req.state().db.read().await;
+        let cap = authorize(&req, &state, Access::Read, &key)?;
+        if let Some(def) = state.computed.get(&name) {
+            require(&cap, Access::Read, &def.script)?;
+        }
+        match state.computed.render(&state.op_db, &state.view, &name, &key) {
+            None => Ok(Response::new(StatusCode::NotFound)),
+            Some(Ok(rendered)) => Ok(Response::builder(StatusCode::Ok)
+                .content_type(rendered.content_type.as_str())
+                .body(rendered.body)
+                .build()),
+            Some(Err(e)) => Ok(Response::builder(StatusCode::UnprocessableEntity)
+                .body(serde_json::json!({ "error": "ScriptError", "message": e }))
+                .build()),
+        }
+    });
+
+    app.at("/derived").get(|req: Request<State>| async move {
+        let state = req.state().db.read().await;
+        let cap = capability(&req, &state)?;
+        let now = capability::now();
+        let derivations: Vec<serde_json::Value> = state.view.derived.iter()
+            .filter(|(_, _, _, output)| cap.allows(Access::Read, output, now))
+            .map(|(name, deriver, prefix, output)| serde_json::json!({
+                "name": name,
+                "deriver": deriver,
+                "prefix": prefix,
+                "output": output,
+            }))
+            .collect();
+
+        Ok(Response::builder(StatusCode::Ok)
+            .body(serde_json::Value::Array(derivations))
+            .build())
+    });
+
+    // Declare a derivation. The body is {"deriver": registered deriver name, "prefix": input key
+    // prefix, "output": output key prefix}. Derived documents are read with GET /doc.
+    app.at("/derived/:name").put(|mut req: Request<State>| async move {
+        let body: serde_json::Value = req.body_json().await?;
+        let name = req.param("name")?.to_string();
+        let field = |f: &str| body.get(f).and_then(|v| v.as_str()).map(|s| s.to_string())
+            .ok_or_else(|| tide::Error::from_str(StatusCode::BadRequest, format!("Missing '{}'", f)));
+        let (deriver, prefix, output) = (field("deriver")?, field("prefix")?, field("output")?);
+
+        let mut state = req.state().db.write().await;
+        let cap = authorize(&req, &state, Access::Read, &prefix)?;
+        require(&cap, Access::Write, &output)?;
+        let changes = state.view.create_derivation(&name, &deriver, &prefix, &output)
+            .map_err(|e| tide::Error::from_str(StatusCode::BadRequest, e))?;
+        state.notify(&changes);
+        Ok(Response::new(StatusCode::Ok))
+    });
+
+    app.at("/indexes").get(|req: Request<State>| async move {
+        let state = req.state().db.read().await;
+        let cap = capability(&req, &state)?;
+        let now = capability::now();
+        let indexes: Vec<serde_json::Value> = state.view.indexes.iter()
+            .filter(|(_, index)| cap.allows(Access::Read, &index.prefix, now))
+            .map(|(name, index)| serde_json::json!({
+                "name": name,
+                "prefix": index.prefix,
+                "path": index.path,
+            }))
+            .collect();
+
+        Ok(Response::builder(StatusCode::Ok)
+            .body(serde_json::Value::Array(indexes))
+            .build())
+    });
+
+    // Declare an index. The body is {"prefix": key prefix, "path": JSON pointer}.
+    app.at("/index/:name").put(|mut req: Request<State>| async move {
+        let body: serde_json::Value = req.body_json().await?;
+        let name = req.param("name")?.to_string();
+        let field = |f: &str| body.get(f).and_then(|v| v.as_str()).map(|s| s.to_string())
+            .ok_or_else(|| tide::Error::from_str(StatusCode::BadRequest, format!("Missing '{}'", f)));
+        let (prefix, path) = (field("prefix")?, field("path")?);
+
+        let mut state = req.state().db.write().await;
+        authorize(&req, &state, Access::Write, &prefix)?;
+        state.view.create_index(&name, &prefix, &path)
+            .map_err(|e| tide::Error::from_str(StatusCode::BadRequest, e))?;
+        Ok(Response::new(StatusCode::Ok))
+    });
+
+    // Query an index by exact value (?eq=) or by range (?from=&to=, half open). Query values are
+    // parsed as JSON if possible, so ?eq=5 matches the number 5 and ?eq=alice matches "alice".
+    // Subscriptions work like they do for /docs.
+    app.at("/index/:name").get(|req: Request<State>| async move {
+        let name = req.param("name")?.to_string();
+        let mut query = IndexQuery::default();
+        let mut limit = DEFAULT_LIST_LIMIT;
+
+        let parse = |v: &str| -> tide::Result<Option<IndexValue>> {
+            let json = serde_json::from_str(v).unwrap_or_else(|_| serde_json::Value::String(v.to_string()));
+            IndexValue::from_json(&json).map(Some)
+                .ok_or_else(|| tide::Error::from_str(StatusCode::BadRequest, "Only scalar values are indexed"))
+        };
+        for (k, v) in req.url().query_pairs() {
+            match k.as_ref() {
+                "eq" => query.eq = parse(&v)?,
+                "from" => query.from = parse(&v)?,
+                "to" => query.to = parse(&v)?,
+                "limit" => limit = v.parse::<usize>()
+                    .map_err(|_| tide::Error::from_str(StatusCode::BadRequest, "Invalid limit"))?
+                    .min(MAX_LIST_LIMIT),
+                _ => {}
+            }
+        }
+
+        if req.header("Subscribe").is_some() {
+            let mut state = req.state().db.write().await;
+            let cap = capability(&req, &state)?;
+            match state.view.indexes.get(&name) {
+                Some(index) => require(&cap, Access::Subscribe, &index.prefix)?,
+                None => return Ok(Response::new(StatusCode::NotFound)),
+            }
+            return Ok(subscribe(&mut state, Target::Index { name, query }, cap));
+        }
+
+        let state = req.state().db.read().await;
+        let cap = capability(&req, &state)?;
+        let index = match state.view.indexes.get(&name) {
+            Some(index) => index,
+            None => return Ok(Response::new(StatusCode::NotFound)),
+        };
+        require(&cap, Access::Read, &index.prefix)?;
+
+        let entries: Vec<serde_json::Value> = index.query(&query)
+            .take(limit)
+            .map(|(value, key)| serde_json::json!({ "key": key, "value": value.to_json() }))
+            .collect();
+
+        Ok(Response::builder(StatusCode::Ok)
+            .body(serde_json::json!({ "docs": entries }))
+            .build())
+    });
+
+    // Attenuate the request's capability. The body lists the caveats to add: {"prefix": key
+    // prefix, "access": ["read", "write", "subscribe"], "expires": unix time}, all optional.
+    // Holders can also do this themselves (see capability::Capability::attenuate). The response
+    // includes the new capability's holder, which is how it's named in presence.
+    app.at("/capability").post(|mut req: Request<State>| async move {
+        let body: serde_json::Value = req.body_json().await?;
+        let state = req.state().db.read().await;
+        let mut cap = capability(&req, &state)?;
+        let bad_request = |msg: &str| tide::Error::from_str(StatusCode::BadRequest, msg.to_string());
+
+        if let Some(prefix) = body.get("prefix") {
+            let prefix = prefix.as_str().ok_or_else(|| bad_request("prefix must be a string"))?;
+            cap = cap.attenuate(Caveat::Prefix(prefix.to_string()));
+        }
+        if let Some(access) = body.get("access") {
+            let access = access.as_array()
+                .and_then(|a| a.iter().map(|a| a.as_str().and_then(Access::parse)).collect::<Option<Vec<Access>>>())
+                .ok_or_else(|| bad_request("access must be a list of 'read', 'write' or 'subscribe'"))?;
+            cap = cap.attenuate(Caveat::Access(access));
+        }
+        if let Some(expires) = body.get("expires") {
+            let expires = expires.as_u64().ok_or_else(|| bad_request("expires must be a unix time"))?;
+            cap = cap.attenuate(Caveat::Expires(expires));
+        }
+
+        Ok(Response::builder(StatusCode::Ok)
+            .body(serde_json::json!({
+                "token": cap.encode(),
+                "holder": cap.holder(),
+                "caveats": cap.caveats().iter().map(|c| c.to_string()).collect::<Vec<String>>(),
+            }))
+            .build())
+    });
+
+    // Our frontier, for comparing with a peer. If the hashes match, the peers are in sync.
+    // Otherwise the peer can send the sample back to GET /sync/ops (as ?have=) to find out where
+    // they diverged. Hashes are hex encoded.
+    app.at("/sync/frontier").get(|req: Request<State>| async move {
+        let state = req.state().db.read().await;
+        authorize(&req, &state, Access::Read, "")?;
+        let ops = &state.op_db;
+        let heads: Vec<serde_json::Value> = ops.frontier().iter()
+            .map(|order| serde_json::json!({
+                "version": ops.order_to_remote_version(*order).encode(),
+                "hash": merkle::to_hex(&ops.op_hash(*order)),
+            }))
+            .collect();
+
+        Ok(Response::builder(StatusCode::Ok)
+            .body(serde_json::json!({
+                "hash": merkle::to_hex(&ops.frontier_hash()),
+                "heads": heads,
+                "sample": ops.sample_hashes().iter().map(merkle::to_hex).collect::<Vec<String>>(),
+            }))
+            .build())
+    });
+
+    // Sync. Operations are sent between peers as MessagePack encoded lists of RemoteOperations.
+    // GET returns the operations the peer doesn't have, given the versions it's at (?since=, as
+    // encoded by encode_versions) or the hashes of operations it has (?have=, '.' separated). The
+    // response's parents header names the versions we found in common. This exposes every
+    // document, so it needs read access to all keys.
+    app.at("/sync/ops").get(|req: Request<State>| async move {
+        let state = req.state().db.read().await;
+        authorize(&req, &state, Access::Read, "")?;
+        let bad_request = |msg: &str| tide::Error::from_str(StatusCode::BadRequest, msg.to_string());
+
+        // Versions we don't know are skipped. We'll send the peer more than it needs, and it'll
+        // ignore the operations it already has.
+        let mut since: Vec<Order> = match query_param(&req, "since") {
+            Some(since) => decode_versions(&since)
+                .ok_or_else(|| bad_request("Invalid version"))?
+                .iter()
+                .filter_map(|v| state.op_db.remote_version_to_order(v))
+                .collect(),
+            None => vec!(),
+        };
+        if let Some(have) = query_param(&req, "have") {
+            let hashes = merkle::from_hex_list(&have).ok_or_else(|| bad_request("Invalid hash"))?;
+            since.extend(state.op_db.common_versions(&hashes));
+        }
+        let common: Vec<RemoteVersion> = since.iter()
+            .map(|order| state.op_db.order_to_remote_version(*order))
+            .collect();
+        // If the peer is behind a frontier we've pruned, we can't bring it up to date.
+        let ops = state.op_db.ops_since(&since).into_iter()
+            .map(|order| state.op_db.remote_operation(order))
+            .collect::<Result<Vec<RemoteOperation>, DbError>>();
+        let ops = match ops {
+            Ok(ops) => ops,
+            Err(e) => return Ok(error_response(&e)),
+        };
+
+        Ok(Response::builder(StatusCode::Ok)
+            .content_type(SYNC_CONTENT_TYPE)
+            .header("version", encode_versions(&state.branch_versions()).as_str())
+            .header("parents", encode_versions(&common).as_str())
+            .body(rmp_serde::to_vec(&ops)?)
+            .build())
+    });
+
+    // Receive operations from a peer, in causal order. Every document they touch must be
+    // writable. Operations are applied one at a time, so if one is rejected the ones before it
+    // have still been applied.
+    app.at("/sync/ops").post(|mut req: Request<State>| async move {
+        let ops: Vec<RemoteOperation> = rmp_serde::from_slice(&req.body_bytes().await?)
+            .map_err(|e| tide::Error::from_str(StatusCode::BadRequest, format!("Invalid operations: {}", e)))?;
+        let mut state = req.state().db.write().await;
+        let cap = capability(&req, &state)?;
+        for op in ops.iter() {
+            // Operations which don't write any documents still change the frontier, so they need
+            // write access to everything.
+            if op.doc_ops.is_empty() { require(&cap, Access::Write, "")?; }
+            for doc_op in op.doc_ops.iter() {
+                require(&cap, Access::Write, &doc_op.id)?;
+            }
+        }
+
+        for op in ops.iter() {
+            if let Err(e) = state.apply_and_advance(op) { return Ok(error_response(&e)); }
+        }
+        Ok(Response::builder(StatusCode::Ok)
+            .header("version", encode_versions(&state.branch_versions()).as_str())
+            .body("")
+            .build())
+    });
 
-        let mut res = Response::new(StatusCode::Ok);
-        res.insert_header("cache-control", "no-cache");
-        res.insert_header("connection", "keep-alive");
-        res.insert_header("content-type", "text/plain");
+    // Prune history before a frontier every peer has acknowledged. The body is the frontier's
+    // versions (as encoded by encode_versions). Peers behind it can't sync with us afterwards, and
+    // reading or undoing older versions fails with HistoryPruned.
+    app.at("/sync/prune").post(|mut req: Request<State>| async move {
+        let body = req.body_string().await?;
+        let frontier = decode_versions(body.trim())
+            .ok_or_else(|| tide::Error::from_str(StatusCode::BadRequest, "Invalid version"))?;
 
-        let body = Body::from_reader(BufReader::new(reader), None);
-        res.set_body(body);
+        let mut state = req.state().db.write().await;
+        authorize(&req, &state, Access::Write, "")?;
+        match state.prune(&frontier) {
+            Ok(discarded) => Ok(Response::builder(StatusCode::Ok)
+                .body(serde_json::json!({ "discarded": discarded }))
+                .build()),
+            Err(e) => Ok(error_response(&e)),
+        }
+    });
 
-        Ok(res)
+    // The public keys agents sign their operations with, as {agent: base64 key}.
+    app.at("/agents").get(|req: Request<State>| async move {
+        let state = req.state().db.read().await;
+        capability(&req, &state)?;
+        let keys: serde_json::Map<String, serde_json::Value> = state.op_db.keys.iter()
+            .map(|(agent, key)| (agent.clone(), base64::encode(key.as_bytes()).into()))
+            .collect();
+
+        Ok(Response::builder(StatusCode::Ok)
+            .body(serde_json::Value::Object(keys))
+            .build())
     });
 
-    app.at("/sse").get(tide::sse::endpoint(|_req, sender| async move {
-        sender.send("", "oh hai", None).await?;
-        async_std::task::sleep(std::time::Duration::from_secs(2)).await;
-        sender.send("", "yooo", None).await?;
+    // Bind an agent to a public key, so we accept its operations. The body is the base64 encoded
+    // ed25519 public key. This affects every document, so it needs write access to all keys.
+    app.at("/agents/:agent").put(|mut req: Request<State>| async move {
+        let body = req.body_string().await?;
+        let agent = req.param("agent")?.to_string();
+        let key = signing::parse_public_key(&body)
+            .ok_or_else(|| tide::Error::from_str(StatusCode::BadRequest, "Expected a base64 encoded ed25519 public key"))?;
 
-        Ok(())
-    }));
+        let mut state = req.state().db.write().await;
+        authorize(&req, &state, Access::Write, "")?;
+        match state.bind_agent(&agent, key) {
+            Ok(()) => Ok(Response::new(StatusCode::Ok)),
+            Err(DbError::InvalidOperation(e)) => Err(tide::Error::from_str(StatusCode::Conflict, e)),
+            Err(e) => Ok(error_response(&e)),
+        }
+    });
+
+    // Ownership rules are configured at startup (see BRAID_OWNERS), because every peer needs the
+    // same rules.
+    app.at("/owners").get(|req: Request<State>| async move {
+        let state = req.state().db.read().await;
+        capability(&req, &state)?;
+        let patterns: Vec<&str> = state.op_db.owners.iter().collect();
+
+        Ok(Response::builder(StatusCode::Ok)
+            .body(serde_json::json!(patterns))
+            .build())
+    });
+
+    app.at("/history/*key").get(|req: Request<State>| async move {
+        let key = req.param("key")?.to_string();
+        let state = req.state().db.read().await;
+        authorize(&req, &state, Access::Read, &key)?;
+        // Derived documents aren't written by operations, so they have no history.
+        if state.view.derived.get(&key).is_some() {
+            return Ok(Response::new(StatusCode::NotFound));
+        }
+
+        let heads: Vec<Order> = state.view.get_cloned(&key).iter().map(|v| v.order).collect();
+        let history: Vec<serde_json::Value> = state.op_db.doc_history(&key, &heads)
+            .into_iter()
+            .map(|order| {
+                let op = state.op_db.operation_by_order(order);
+                let doc_op = doc_op_entry(&op.doc_ops[..], &key).unwrap();
+                let version = state.op_db.order_to_remote_version(order);
+
+                serde_json::json!({
+                    "version": version.encode(),
+                    "agent": version.agent,
+                    "seq": version.seq,
+                    "parents": doc_op.parents.iter()
+                        .map(|p| state.op_db.order_to_remote_version(*p).encode())
+                        .collect::<Vec<String>>(),
+                    "deleted": doc_op.patch == Some(DocPatch::Replace(DocValue::None)),
+                    "pruned": doc_op.patch.is_none(),
+                })
+            })
+            .collect();
+
+        Ok(Response::builder(StatusCode::Ok)
+            .body(serde_json::Value::Array(history))
+            .build())
+    });
 
     app.listen("0.0.0.0:4000").await
 }
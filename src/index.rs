@@ -0,0 +1,173 @@
+use crate::types::*;
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+/**
+ * An indexed value. Only JSON scalars are indexed. Values of different types are ordered null <
+ * booleans < numbers < strings, and all numbers are compared as floats.
+ */
+#[derive(Clone, Debug)]
+pub enum IndexValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+impl IndexValue {
+    /** Returns None for arrays and objects. */
+    pub fn from_json(value: &Value) -> Option<IndexValue> {
+        match value {
+            Value::Null => Some(IndexValue::Null),
+            Value::Bool(b) => Some(IndexValue::Bool(*b)),
+            Value::Number(n) => n.as_f64().map(IndexValue::Number),
+            Value::String(s) => Some(IndexValue::String(s.clone())),
+            Value::Array(_) | Value::Object(_) => None,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            IndexValue::Null => Value::Null,
+            IndexValue::Bool(b) => Value::Bool(*b),
+            // Integers were indexed as floats. Show them as integers again.
+            IndexValue::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => Value::from(*n as i64),
+            IndexValue::Number(n) => Value::from(*n),
+            IndexValue::String(s) => Value::String(s.clone()),
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            IndexValue::Null => 0,
+            IndexValue::Bool(_) => 1,
+            IndexValue::Number(_) => 2,
+            IndexValue::String(_) => 3,
+        }
+    }
+}
+
+impl Ord for IndexValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (IndexValue::Bool(a), IndexValue::Bool(b)) => a.cmp(b),
+            (IndexValue::Number(a), IndexValue::Number(b)) => a.total_cmp(b),
+            (IndexValue::String(a), IndexValue::String(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for IndexValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for IndexValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for IndexValue {}
+
+/**
+ * A secondary index over the field at `path` (a JSON pointer) of every document whose key starts
+ * with `prefix`. If the field holds a list, each scalar in the list is indexed. Documents with
+ * conflicting versions are indexed under the values of all their versions.
+ */
+#[derive(Debug)]
+pub struct Index {
+    pub prefix: String,
+    pub path: String,
+    entries: BTreeSet<(IndexValue, DocId)>,
+    /** The values each document is currently indexed under, so we can remove them again. */
+    by_doc: BTreeMap<DocId, Vec<IndexValue>>,
+}
+
+impl Index {
+    pub fn new(prefix: String, path: String) -> Self {
+        Index { prefix, path, entries: BTreeSet::new(), by_doc: BTreeMap::new() }
+    }
+
+    pub fn covers(&self, key: &DocId) -> bool {
+        key.starts_with(&self.prefix)
+    }
+
+    /** The values a document with these (concurrent) versions is indexed under, sorted. */
+    pub fn values_for(&self, values: &[DocValue]) -> Vec<IndexValue> {
+        let mut result: Vec<IndexValue> = Vec::new();
+        for json in values.iter().filter_map(|v| v.json()) {
+            match json.pointer(&self.path) {
+                Some(Value::Array(items)) => result.extend(items.iter().filter_map(IndexValue::from_json)),
+                Some(field) => result.extend(IndexValue::from_json(field)),
+                None => {},
+            }
+        }
+        result.sort();
+        result.dedup();
+        result
+    }
+
+    /** Update the entries for a document. Values are the document's current versions. */
+    pub fn update(&mut self, key: &DocId, values: &[DocValue]) {
+        if let Some(old) = self.by_doc.remove(key) {
+            for v in old {
+                self.entries.remove(&(v, key.clone()));
+            }
+        }
+
+        let new = self.values_for(values);
+        if !new.is_empty() {
+            for v in new.iter() {
+                self.entries.insert((v.clone(), key.clone()));
+            }
+            self.by_doc.insert(key.clone(), new);
+        }
+    }
+
+    /**
+     * Iterate through (value, key) entries with values in the range [from, to). Either end can be
+     * left open.
+     */
+    pub fn range(&self, from: Option<IndexValue>, to: Option<IndexValue>) -> impl Iterator<Item=(&IndexValue, &DocId)> {
+        // DocIds sort after the empty string, so (from, "") is before every entry for from.
+        let start = match from {
+            Some(v) => Bound::Included((v, DocId::new())),
+            None => Bound::Unbounded,
+        };
+        let end = match to {
+            Some(v) => Bound::Excluded((v, DocId::new())),
+            None => Bound::Unbounded,
+        };
+        self.entries.range((start, end)).map(|(v, key)| (v, key))
+    }
+
+
+    /** Iterate through the (value, key) entries matching a query. */
+    pub fn query<'a>(&'a self, query: &'a IndexQuery) -> impl Iterator<Item=(&'a IndexValue, &'a DocId)> + 'a {
+        self.range(query.eq.clone().or_else(|| query.from.clone()), query.to.clone())
+            .take_while(move |(value, _)| query.eq.as_ref().is_none_or(|eq| *value == eq))
+    }
+}
+
+/** A query on an index. This matches values equal to eq, or in the half open range [from, to). */
+#[derive(Clone, Debug, Default)]
+pub struct IndexQuery {
+    pub eq: Option<IndexValue>,
+    pub from: Option<IndexValue>,
+    pub to: Option<IndexValue>,
+}
+
+impl IndexQuery {
+    pub fn matches(&self, value: &IndexValue) -> bool {
+        match &self.eq {
+            Some(eq) => value == eq,
+            None => self.from.as_ref().is_none_or(|from| value >= from)
+                && self.to.as_ref().is_none_or(|to| value < to),
+        }
+    }
+}
@@ -0,0 +1,457 @@
+use crate::types::*;
+use crate::rga::{Rga, ItemId};
+use serde_json::{Value, Map};
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+
+/** How concurrent writes to the same field are shown to readers. */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RegisterMode {
+    /** The write with the highest (lamport, id) wins. */
+    LastWriterWins,
+    /** All concurrent values are kept, and shown as `{"$conflict": [...]}`. */
+    MultiValue,
+}
+
+/**
+ * Names an object or list element. Items created earlier in the same patch are named by their
+ * offset in the patch, because the patch's version isn't known when it's generated.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ItemRef {
+    Existing(ItemId),
+    New(u32),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NewValue {
+    Value(Value),
+    Map,
+    List,
+}
+
+/**
+ * JSON CRDT operations. Each operation in a patch creates at most one item, whose ID is made from
+ * the patch's version and the operation's offset in the patch. Objects are named by the ID of the
+ * operation which created them, or None for the root object.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JsonOp {
+    /**
+     * Set a field in a map, replacing the named (previously observed) values. Setting a field to
+     * None deletes it.
+     */
+    Set { obj: Option<ItemRef>, key: String, value: Option<NewValue>, replaces: Vec<ItemId> },
+    ListInsert { obj: Option<ItemRef>, origin: Option<ItemRef>, value: NewValue },
+    ListDelete { obj: Option<ItemRef>, target: ItemId },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum Leaf {
+    Value(Value),
+    Obj(ItemId),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    lamport: u64,
+    /** None once the entry has been replaced. We keep the tombstone so merges don't revive it. */
+    value: Option<Leaf>,
+}
+
+/** A register is the set of values written to a map field. Concurrent writes are all kept. */
+type Register = BTreeMap<ItemId, Entry>;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum Obj {
+    Map(BTreeMap<String, Register>),
+    List(Rga<Leaf>),
+}
+
+/**
+ * A replicated JSON document made of maps, lists and registers. Concurrent edits to different
+ * fields both survive, and concurrent edits to the same field are resolved by the document's
+ * register mode. As with text documents, any two states can be merged.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JsonDoc {
+    pub mode: RegisterMode,
+    /** All objects in the document, including the root map (None). */
+    objects: BTreeMap<Option<ItemId>, Obj>,
+    max_lamport: u64,
+}
+
+/** The key conflicting values are shown under in MultiValue mode. Documents can't contain it. */
+const CONFLICT_KEY: &str = "$conflict";
+
+/** Check a value written to a document doesn't contain a (rendered) conflict. */
+fn check_no_conflict(value: &Value) -> Result<(), String> {
+    match value {
+        Value::Object(fields) => {
+            if fields.contains_key(CONFLICT_KEY) {
+                return Err(format!("{} can't be used as a field name", CONFLICT_KEY));
+            }
+            fields.values().try_for_each(check_no_conflict)
+        },
+        Value::Array(items) => items.iter().try_for_each(check_no_conflict),
+        _ => Ok(()),
+    }
+}
+
+fn live_entries(reg: &Register) -> Vec<(&ItemId, &Entry, &Leaf)> {
+    let mut entries: Vec<(&ItemId, &Entry, &Leaf)> = reg.iter()
+        .filter_map(|(id, e)| e.value.as_ref().map(|v| (id, e, v)))
+        .collect();
+    entries.sort_by(|(a_id, a, _), (b_id, b, _)| (a.lamport, a_id).cmp(&(b.lamport, b_id)));
+    entries
+}
+
+impl JsonDoc {
+    pub fn new(mode: RegisterMode) -> Self {
+        let mut objects = BTreeMap::new();
+        objects.insert(None, Obj::Map(BTreeMap::new()));
+        JsonDoc { mode, objects, max_lamport: 0 }
+    }
+
+    fn leaf_to_json(&self, leaf: &Leaf) -> Value {
+        match leaf {
+            Leaf::Value(v) => v.clone(),
+            Leaf::Obj(id) => self.obj_to_json(&Some(id.clone())),
+        }
+    }
+
+    fn obj_to_json(&self, id: &Option<ItemId>) -> Value {
+        match &self.objects[id] {
+            Obj::Map(fields) => Value::Object(fields.iter()
+                .filter_map(|(key, reg)| self.register_to_json(reg).map(|value| (key.clone(), value)))
+                .collect()),
+            Obj::List(items) => Value::Array(items.visible().map(|leaf| self.leaf_to_json(leaf)).collect()),
+        }
+    }
+
+    /** The value a map field is shown as, or None if the field has no values. */
+    fn register_to_json(&self, reg: &Register) -> Option<Value> {
+        let entries = live_entries(reg);
+        Some(match (self.mode, entries.len()) {
+            (_, 0) => return None,
+            (_, 1) | (RegisterMode::LastWriterWins, _) => self.leaf_to_json(entries.last().unwrap().2),
+            (RegisterMode::MultiValue, _) => serde_json::json!({
+                CONFLICT_KEY: entries.iter().map(|(_, _, leaf)| self.leaf_to_json(leaf)).collect::<Vec<Value>>()
+            }),
+        })
+    }
+
+    /** The current value of the document as plain JSON. */
+    pub fn to_json(&self) -> Value {
+        self.obj_to_json(&None)
+    }
+
+    /** Merge another replica's state into this one. */
+    pub fn merge(&mut self, other: &JsonDoc) -> Result<(), String> {
+        for (id, other_obj) in other.objects.iter() {
+            match (self.objects.get_mut(id), other_obj) {
+                (None, obj) => { self.objects.insert(id.clone(), obj.clone()); },
+                (Some(Obj::Map(fields)), Obj::Map(other_fields)) => {
+                    for (key, other_reg) in other_fields {
+                        let reg = fields.entry(key.clone()).or_default();
+                        for (entry_id, other_entry) in other_reg {
+                            match reg.get_mut(entry_id) {
+                                // Replaced entries stay replaced.
+                                Some(entry) => if other_entry.value.is_none() { entry.value = None; },
+                                None => { reg.insert(entry_id.clone(), other_entry.clone()); },
+                            }
+                        }
+                    }
+                },
+                (Some(Obj::List(items)), Obj::List(other_items)) => items.merge(other_items),
+                _ => return Err("Object kinds differ between replicas".to_string()),
+            }
+        }
+        self.max_lamport = self.max_lamport.max(other.max_lamport);
+        Ok(())
+    }
+
+    /** Apply operations from the patch written by the operation with the specified version. */
+    pub fn apply(&mut self, ops: &[JsonOp], version: &RemoteVersion) -> Result<(), String> {
+        let lamport = self.max_lamport + 1;
+        let resolve = |r: &ItemRef| match r {
+            ItemRef::Existing(id) => id.clone(),
+            ItemRef::New(offset) => ItemId::new(version, *offset),
+        };
+
+        for (offset, op) in ops.iter().enumerate() {
+            let id = ItemId::new(version, offset as u32);
+
+            // Make a leaf for the new value, creating an empty object if needed.
+            let mut new_leaf = |value: &NewValue| -> Leaf {
+                match value {
+                    NewValue::Value(v) => Leaf::Value(v.clone()),
+                    NewValue::Map => {
+                        self.objects.insert(Some(id.clone()), Obj::Map(BTreeMap::new()));
+                        Leaf::Obj(id.clone())
+                    },
+                    NewValue::List => {
+                        self.objects.insert(Some(id.clone()), Obj::List(Rga::new()));
+                        Leaf::Obj(id.clone())
+                    },
+                }
+            };
+
+            match op {
+                JsonOp::Set { obj, key, value, replaces } => {
+                    if key == CONFLICT_KEY {
+                        return Err(format!("{} can't be used as a field name", CONFLICT_KEY));
+                    }
+                    if let Some(NewValue::Value(v)) = value { check_no_conflict(v)?; }
+                    let leaf = value.as_ref().map(&mut new_leaf);
+                    let obj_id = obj.as_ref().map(resolve);
+                    let reg = match self.objects.get_mut(&obj_id) {
+                        Some(Obj::Map(fields)) => fields.entry(key.clone()).or_default(),
+                        _ => return Err("Set target is not a map".to_string()),
+                    };
+
+                    for r in replaces {
+                        reg.get_mut(r).ok_or_else(|| format!("Unknown value {}/{}/{}", r.agent, r.seq, r.offset))?
+                            .value = None;
+                    }
+                    if let Some(leaf) = leaf {
+                        reg.insert(id.clone(), Entry { lamport, value: Some(leaf) });
+                    }
+                },
+                JsonOp::ListInsert { obj, origin, value } => {
+                    if let NewValue::Value(v) = value { check_no_conflict(v)?; }
+                    let leaf = new_leaf(value);
+                    let obj_id = obj.as_ref().map(resolve);
+                    match self.objects.get_mut(&obj_id) {
+                        Some(Obj::List(items)) => items.insert(id.clone(), origin.as_ref().map(resolve), lamport, leaf)?,
+                        _ => return Err("Insert target is not a list".to_string()),
+                    }
+                },
+                JsonOp::ListDelete { obj, target } => {
+                    let obj_id = obj.as_ref().map(resolve);
+                    match self.objects.get_mut(&obj_id) {
+                        Some(Obj::List(items)) => items.delete(target)?,
+                        _ => return Err("Delete target is not a list".to_string()),
+                    }
+                },
+            }
+        }
+
+        self.max_lamport = lamport;
+        Ok(())
+    }
+
+    /** Generate operations to change this document's value to target. */
+    pub fn diff_ops(&self, target: &Value) -> Result<Vec<JsonOp>, String> {
+        let target = target.as_object()
+            .ok_or_else(|| "JSON CRDT documents must be objects".to_string())?;
+
+        let mut ops = Vec::new();
+        self.diff_map(None, &None, target, &mut ops)?;
+        Ok(ops)
+    }
+
+    /**
+     * Fields which are unchanged from how they're shown are left alone, so fields with conflicting
+     * values keep them until they're written.
+     */
+    fn diff_map(&self, obj_ref: Option<ItemRef>, obj_id: &Option<ItemId>, target: &Map<String, Value>, ops: &mut Vec<JsonOp>) -> Result<(), String> {
+        let empty = BTreeMap::new();
+        let fields = match &self.objects[obj_id] {
+            Obj::Map(fields) => fields,
+            _ => &empty,
+        };
+
+        // Remove deleted fields.
+        for (key, reg) in fields {
+            let entries = live_entries(reg);
+            if !entries.is_empty() && !target.contains_key(key) {
+                ops.push(JsonOp::Set {
+                    obj: obj_ref.clone(),
+                    key: key.clone(),
+                    value: None,
+                    replaces: entries.iter().map(|(id, _, _)| (*id).clone()).collect()
+                });
+            }
+        }
+
+        for (key, value) in target {
+            let reg = fields.get(key);
+            if reg.and_then(|reg| self.register_to_json(reg)).as_ref() == Some(value) { continue; }
+
+            let entries = reg.map(live_entries).unwrap_or_default();
+            if entries.len() == 1 {
+                let (id, _, leaf) = entries[0];
+
+                // Edit nested objects in place, so concurrent edits inside them both survive.
+                if let Leaf::Obj(child) = leaf {
+                    match (&self.objects[&Some(child.clone())], value) {
+                        (Obj::Map(_), Value::Object(child_target)) => {
+                            self.diff_map(Some(ItemRef::Existing(id.clone())), &Some(child.clone()), child_target, ops)?;
+                            continue;
+                        },
+                        (Obj::List(_), Value::Array(child_target)) => {
+                            self.diff_list(ItemRef::Existing(id.clone()), child, child_target, ops)?;
+                            continue;
+                        },
+                        _ => {}
+                    }
+                }
+            }
+
+            if key == CONFLICT_KEY {
+                return Err(format!("{} can't be used as a field name", CONFLICT_KEY));
+            }
+            check_no_conflict(value)?;
+            ops.push(JsonOp::Set {
+                obj: obj_ref.clone(),
+                key: key.clone(),
+                value: Some(new_value_for(value)),
+                replaces: entries.iter().map(|(id, _, _)| (*id).clone()).collect()
+            });
+            Self::fill_new(ops.len() as u32 - 1, value, ops);
+        }
+        Ok(())
+    }
+
+    fn diff_list(&self, obj_ref: ItemRef, obj_id: &ItemId, target: &[Value], ops: &mut Vec<JsonOp>) -> Result<(), String> {
+        let items = match &self.objects[&Some(obj_id.clone())] {
+            Obj::List(items) => items,
+            _ => unreachable!(),
+        };
+        let ids = items.visible_ids();
+        let old: Vec<Value> = items.visible().map(|leaf| self.leaf_to_json(leaf)).collect();
+
+        let prefix = old.iter().zip(target.iter()).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..].iter().rev().zip(target[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b).count();
+
+        for id in &ids[prefix..old.len() - suffix] {
+            ops.push(JsonOp::ListDelete { obj: Some(obj_ref.clone()), target: (*id).clone() });
+        }
+
+        let mut origin = if prefix == 0 { None } else { Some(ItemRef::Existing(ids[prefix - 1].clone())) };
+        for value in &target[prefix..target.len() - suffix] {
+            check_no_conflict(value)?;
+            ops.push(JsonOp::ListInsert {
+                obj: Some(obj_ref.clone()),
+                origin: origin.clone(),
+                value: new_value_for(value)
+            });
+            let offset = ops.len() as u32 - 1;
+            Self::fill_new(offset, value, ops);
+            origin = Some(ItemRef::New(offset));
+        }
+        Ok(())
+    }
+
+    /** Populate an object created at the specified offset in the patch. */
+    fn fill_new(offset: u32, value: &Value, ops: &mut Vec<JsonOp>) {
+        match value {
+            Value::Object(fields) => {
+                for (key, child) in fields {
+                    ops.push(JsonOp::Set {
+                        obj: Some(ItemRef::New(offset)),
+                        key: key.clone(),
+                        value: Some(new_value_for(child)),
+                        replaces: vec!()
+                    });
+                    Self::fill_new(ops.len() as u32 - 1, child, ops);
+                }
+            },
+            Value::Array(items) => {
+                let mut origin = None;
+                for child in items {
+                    ops.push(JsonOp::ListInsert {
+                        obj: Some(ItemRef::New(offset)),
+                        origin: origin.clone(),
+                        value: new_value_for(child)
+                    });
+                    let child_offset = ops.len() as u32 - 1;
+                    Self::fill_new(child_offset, child, ops);
+                    origin = Some(ItemRef::New(child_offset));
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+fn new_value_for(value: &Value) -> NewValue {
+    match value {
+        Value::Object(_) => NewValue::Map,
+        Value::Array(_) => NewValue::List,
+        v => NewValue::Value(v.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn version(agent: &str, seq: Seq) -> RemoteVersion {
+        RemoteVersion { agent: agent.to_string(), seq }
+    }
+
+    /** Change a copy of doc to target, as the operation with the specified version. */
+    fn edit(doc: &JsonDoc, target: Value, version: &RemoteVersion) -> JsonDoc {
+        let ops = doc.diff_ops(&target).unwrap();
+        let mut doc = doc.clone();
+        doc.apply(&ops, version).unwrap();
+        doc
+    }
+
+    /** Merge two replicas in both orders, check they agree, and return the merged value. */
+    fn converge(a: &JsonDoc, b: &JsonDoc) -> Value {
+        let mut ab = a.clone();
+        ab.merge(b).unwrap();
+        let mut ba = b.clone();
+        ba.merge(a).unwrap();
+        assert_eq!(ab, ba);
+        ab.to_json()
+    }
+
+    fn base(mode: RegisterMode) -> JsonDoc {
+        edit(&JsonDoc::new(mode), json!({"name": "x", "tags": ["a", "b"], "meta": {"n": 1}}), &version("a", 0))
+    }
+
+    #[test]
+    fn concurrent_fields_converge() {
+        let base = base(RegisterMode::LastWriterWins);
+        let b = edit(&base, json!({"name": "y", "tags": ["a", "b"], "meta": {"n": 1}}), &version("b", 0));
+        let c = edit(&base, json!({"name": "x", "tags": ["a", "b"], "meta": {"n": 1, "m": 2}}), &version("c", 0));
+        assert_eq!(converge(&b, &c), json!({"name": "y", "tags": ["a", "b"], "meta": {"n": 1, "m": 2}}));
+    }
+
+    #[test]
+    fn concurrent_writes_converge() {
+        for mode in [RegisterMode::LastWriterWins, RegisterMode::MultiValue] {
+            let base = base(mode);
+            let b = edit(&base, json!({"name": "b", "tags": ["a", "b"], "meta": {"n": 1}}), &version("b", 0));
+            let c = edit(&base, json!({"name": "c", "tags": ["a", "b"], "meta": {"n": 1}}), &version("c", 0));
+            let name = converge(&b, &c)["name"].clone();
+            match mode {
+                RegisterMode::LastWriterWins => assert_eq!(name, json!("c")),
+                RegisterMode::MultiValue => assert_eq!(name, json!({CONFLICT_KEY: ["b", "c"]})),
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_list_edits_converge() {
+        let base = base(RegisterMode::LastWriterWins);
+        let b = edit(&base, json!({"name": "x", "tags": ["a", "b", "c"], "meta": {"n": 1}}), &version("b", 0));
+        let c = edit(&base, json!({"name": "x", "tags": ["z", "b"], "meta": {"n": 1}}), &version("c", 0));
+        assert_eq!(converge(&b, &c)["tags"], json!(["z", "b", "c"]));
+    }
+
+    #[test]
+    fn delete_and_nested_edit_converge() {
+        let base = base(RegisterMode::LastWriterWins);
+        let b = edit(&base, json!({"name": "x", "tags": ["a", "b"]}), &version("b", 0));
+        let c = edit(&base, json!({"name": "x", "tags": ["a", "b"], "meta": {"n": 2}}), &version("c", 0));
+        // The delete replaces the whole field, so the edit inside it is lost on both replicas.
+        assert_eq!(converge(&b, &c), json!({"name": "x", "tags": ["a", "b"]}));
+    }
+}
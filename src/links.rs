@@ -0,0 +1,166 @@
+use crate::types::*;
+use crate::{ROOT_ORDER, doc_op_entry};
+use crate::op_db::OpDb;
+use crate::view_db::ViewDb;
+use crate::version::decode_versions;
+use crate::patch::merge_values;
+use serde_json::{Value, Map};
+use std::collections::{BTreeMap, BTreeSet};
+
+/**
+ * A reference from inside a JSON document to another document. Links are written as
+ * `{"$link": key}`, or `{"$link": key, "$version": versions}` to pin the link to a version of the
+ * target (versions are encoded as in ETags, see encode_versions).
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Link {
+    pub key: DocId,
+    pub version: Option<Vec<RemoteVersion>>,
+}
+
+impl Link {
+    /** Returns None if the value isn't a well formed link. */
+    pub fn from_json(value: &Value) -> Option<Link> {
+        let obj = value.as_object()?;
+        let key = obj.get("$link")?.as_str()?.to_string();
+        let version = match obj.get("$version") {
+            None => None,
+            Some(v) => Some(decode_versions(v.as_str()?)?),
+        };
+        Some(Link { key, version })
+    }
+}
+
+/** Find all the links in a JSON value. */
+pub fn links_in(value: &Value, out: &mut Vec<Link>) {
+    if let Some(link) = Link::from_json(value) {
+        out.push(link);
+        return;
+    }
+    match value {
+        Value::Array(items) => for item in items { links_in(item, out); },
+        Value::Object(obj) => for v in obj.values() { links_in(v, out); },
+        _ => {},
+    }
+}
+
+/**
+ * The values of the document a link points to. Unpinned links point to the document's current
+ * values, which may conflict. Links pinned to a version which doesn't exist (or whose history has
+ * been pruned, or which are pinned to a derived document) point to nothing.
+ */
+pub(crate) fn target_values(ops: &OpDb, view: &ViewDb, link: &Link) -> Vec<DocValue> {
+    let versions = match &link.version {
+        None => return view.get_resolved(&link.key).into_iter().map(|v| v.value).collect(),
+        Some(versions) => versions,
+    };
+    // Derived documents have no history, so there's nothing to pin.
+    if view.derived.get(&link.key).is_some() { return vec!(DocValue::None); }
+
+    let mut values = Vec::with_capacity(versions.len());
+    for v in versions {
+        let order = match ops.remote_version_to_order(v) {
+            Some(order) => order,
+            None => return vec!(DocValue::None),
+        };
+        // The version must be a version of the linked document.
+        if order != ROOT_ORDER && doc_op_entry(&ops.operation_by_order(order).doc_ops, &link.key).is_none() {
+            return vec!(DocValue::None);
+        }
+        match ops.doc_value_at(order, &link.key) {
+            Ok(value) => values.push(value),
+            Err(_) => return vec!(DocValue::None),
+        }
+    }
+    vec!(merge_values(values).unwrap_or(DocValue::None))
+}
+
+/** The documents used to resolve a link. */
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Visited {
+    /** Every document read, pinned or not. Whoever sees the result must be able to read these. */
+    pub touched: BTreeSet<DocId>,
+    /** The documents whose current value was used, so the result changes when they do. */
+    pub deps: BTreeSet<DocId>,
+}
+
+/**
+ * Get the JSON value of a document with links transcluded (replaced by the values of the
+ * documents they point to), following links up to depth levels deep. Links past that depth are
+ * left alone. Missing and non-JSON documents are null, and conflicting versions are returned as
+ * `{"$conflict": [...]}`.
+ *
+ * The documents used are added to visited.
+ */
+pub(crate) fn resolve(ops: &OpDb, view: &ViewDb, link: &Link, depth: usize, visited: &mut Visited) -> Value {
+    visited.touched.insert(link.key.clone());
+    if link.version.is_none() { visited.deps.insert(link.key.clone()); }
+
+    let mut results: Vec<Value> = target_values(ops, view, link).iter()
+        .map(|v| match v.json() {
+            Some(json) => transclude(ops, view, &json, depth, visited),
+            None => Value::Null,
+        })
+        .collect();
+
+    if results.len() == 1 {
+        results.pop().unwrap()
+    } else {
+        serde_json::json!({ "$conflict": results })
+    }
+}
+
+fn transclude(ops: &OpDb, view: &ViewDb, value: &Value, depth: usize, visited: &mut Visited) -> Value {
+    if depth == 0 { return value.clone(); }
+
+    if let Some(link) = Link::from_json(value) {
+        return resolve(ops, view, &link, depth - 1, visited);
+    }
+    match value {
+        Value::Array(items) => Value::Array(items.iter()
+            .map(|item| transclude(ops, view, item, depth, visited))
+            .collect()),
+        Value::Object(obj) => Value::Object(obj.iter()
+            .map(|(k, v)| (k.clone(), transclude(ops, view, v, depth, visited)))
+            .collect::<Map<String, Value>>()),
+        _ => value.clone(),
+    }
+}
+
+/** Reverse link index. This maps each document to the set of documents which link to it. */
+#[derive(Debug, Default)]
+pub struct Backlinks {
+    by_target: BTreeMap<DocId, BTreeSet<DocId>>,
+    /** The documents each document currently links to, so we can remove them again. */
+    by_source: BTreeMap<DocId, BTreeSet<DocId>>,
+}
+
+impl Backlinks {
+    /** Update the links from a document. Values are the document's current versions. */
+    pub fn update(&mut self, source: &DocId, values: &[DocValue]) {
+        if let Some(old) = self.by_source.remove(source) {
+            for target in old {
+                let sources = self.by_target.get_mut(&target).unwrap();
+                sources.remove(source);
+                if sources.is_empty() { self.by_target.remove(&target); }
+            }
+        }
+
+        let mut links = Vec::new();
+        for json in values.iter().filter_map(|v| v.json()) {
+            links_in(&json, &mut links);
+        }
+        if links.is_empty() { return; }
+
+        let targets: BTreeSet<DocId> = links.into_iter().map(|l| l.key).collect();
+        for target in targets.iter() {
+            self.by_target.entry(target.clone()).or_default().insert(source.clone());
+        }
+        self.by_source.insert(source.clone(), targets);
+    }
+
+    /** The documents which link to target, in key order. */
+    pub fn get(&self, target: &DocId) -> impl Iterator<Item=&DocId> {
+        self.by_target.get(target).into_iter().flatten()
+    }
+}
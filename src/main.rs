@@ -4,13 +4,47 @@ mod op_db;
 mod view_db;
 mod httpserver;
 mod readchannel;
+mod schema;
+mod error;
+mod patch;
+mod rga;
+mod text_crdt;
+mod json_crdt;
+mod counter_crdt;
+mod set_crdt;
+mod owned;
+mod index;
+mod query;
+mod links;
+mod subscriptions;
+mod presence;
+mod computed;
+mod derived;
+mod capability;
+mod signing;
+mod merkle;
+mod ownership;
+mod storage;
+mod ancestry;
 
 use crate::types::*;
 use crate::version::ROOT_AGENT_STR;
 use std::io;
+use std::collections::BTreeSet;
 use crate::op_db::OpDb;
-use crate::view_db::ViewDb;
+use crate::ownership::OwnershipRules;
+use crate::view_db::{ViewDb, DocChange, DeletePolicy};
 use crate::httpserver::host;
+use crate::schema::SchemaRegistry;
+use crate::error::DbError;
+use crate::subscriptions::{Subscriptions, Sources};
+use crate::computed::{ComputedViews, ViewDef};
+use crate::version::encode_versions;
+use crate::rga::ItemId;
+use crate::capability::Authority;
+use crate::signing::LocalKey;
+use crate::storage::{Storage, Record};
+use std::path::Path;
 
 
 pub(crate) const ROOT_AGENT: Agent = Agent::MAX;
@@ -33,17 +67,349 @@ pub(crate) fn doc_op_entry<'a>(entries: &'a[LocalDocOp], needle: &DocId) -> Opti
 pub struct MemDb {
     op_db: OpDb,
     view: ViewDb,
+    schemas: SchemaRegistry,
+    subscriptions: Subscriptions,
+    computed: ComputedViews,
+    authority: Authority,
+    /** The agent our operations are written as (see signing::agent_name). */
+    pub(crate) agent: String,
+    /** The key our local agent signs operations with. */
+    key: LocalKey,
+    /** Where operations are persisted, if anywhere. */
+    storage: Option<Storage>,
+    /** Owned documents with actions we couldn't sequence yet. These are retried later. */
+    unsequenced: BTreeSet<DocId>,
 }
 
 impl MemDb {
     pub fn new() -> Self {
-        Self::default()
+        Self::with_key(LocalKey::default())
     }
 
-    pub fn apply_and_advance(&mut self, op: &RemoteOperation) -> Order {
-        let order = self.op_db.add_operation(&op);
-        self.view.apply_forwards(&self.op_db, order);
-        order
+    pub fn with_key(key: LocalKey) -> Self {
+        let mut db = MemDb { agent: signing::agent_name(&key.public_key()), ..Self::default() };
+        db.op_db.keys.bind(&db.agent, key.public_key()).unwrap();
+        db.key = key;
+        db
+    }
+
+    /**
+     * Open a database persisted in a directory, replaying everything stored there. If a data key
+     * is given, storage is encrypted at rest. If no signing key is given, we use the one kept in
+     * storage.
+     */
+    pub fn open(dir: &Path, data_key: Option<[u8; 32]>, key: Option<LocalKey>) -> io::Result<Self> {
+        let (storage, records) = Storage::open(dir, data_key)?;
+        let key = match key {
+            Some(key) => key,
+            None => LocalKey::from_seed(storage.signing_seed()?),
+        };
+
+        let mut db = Self::with_key(key);
+        let mut bound = false;
+        for record in records {
+            bound |= matches!(&record, Record::BindAgent { agent, .. } if *agent == db.agent);
+            db.replay(record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        }
+        db.storage = Some(storage);
+
+        // Our key has to be stored too, or our operations couldn't be checked if we're opened
+        // with a different key later. A checkpoint puts it before any operations already stored.
+        if !bound {
+            db.checkpoint().map_err(|e| io::Error::other(e.to_string()))?;
+        }
+        Ok(db)
+    }
+
+    /**
+     * Apply a stored record. Stored operations were validated when they were added, but their
+     * hashes and signatures are checked again in case the files have been changed.
+     */
+    fn replay(&mut self, record: Record) -> Result<(), DbError> {
+        match record {
+            Record::Op(op) => {
+                if self.op_db.existing(&op.version, &op.hash)?.is_some() { return Ok(()); }
+                self.check_references(&op)?;
+                let order = self.op_db.add_operation(&op)?;
+                self.view.apply_forwards(&self.op_db, order);
+            },
+            Record::Pruned(op) => {
+                if self.op_db.existing(&op.version, &op.hash)?.is_some() { return Ok(()); }
+                let order = self.op_db.add_pruned(&op)?;
+                self.view.apply_forwards(&self.op_db, order);
+            },
+            Record::BindAgent { agent, key } => {
+                let key = signing::public_key_from_bytes(&key)
+                    .ok_or_else(|| DbError::InvalidOperation(format!("Invalid key for {}", agent)))?;
+                self.op_db.keys.bind(&agent, key).map_err(DbError::InvalidOperation)?;
+            },
+            Record::OwnershipRule(pattern) => {
+                self.op_db.owners.add(&pattern).map_err(DbError::InvalidOperation)?;
+            },
+        }
+        Ok(())
+    }
+
+    /**
+     * Write a record to storage, if we have any. This happens before the change is made in memory,
+     * so if it fails nothing has changed. Every so often we write a checkpoint first.
+     */
+    fn persist(&mut self, record: Record) -> Result<(), DbError> {
+        if self.storage.as_ref().is_some_and(|storage| storage.needs_checkpoint()) {
+            self.checkpoint()?;
+        }
+        match &mut self.storage {
+            Some(storage) => storage.append(&record).map_err(|e| DbError::Storage(e.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Replace everything in storage with the current state of the op store. Ownership rules go
+     * last, because operations added before a rule don't have to follow it.
+     */
+    fn checkpoint(&mut self) -> Result<(), DbError> {
+        let mut records: Vec<Record> = self.op_db.keys.iter()
+            .map(|(agent, key)| Record::BindAgent { agent: agent.clone(), key: key.to_bytes() })
+            .collect();
+        records.extend(self.op_db.ops_since(&[]).into_iter()
+            .map(|order| match self.op_db.remote_operation(order) {
+                Ok(op) => Record::Op(op),
+                Err(_) => Record::Pruned(self.op_db.pruned_operation(order)),
+            }));
+        records.extend(self.op_db.owners.iter().map(|pattern| Record::OwnershipRule(pattern.to_string())));
+        match &mut self.storage {
+            Some(storage) => storage.checkpoint(&records).map_err(|e| DbError::Storage(e.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Discard history before a frontier every peer has acknowledged (see OpDb::prune). Storage is
+     * checkpointed, so the discarded patches are removed from it too. (If that fails, storage
+     * still has the full history.) Returns how many patches were discarded.
+     */
+    pub fn prune(&mut self, frontier: &[RemoteVersion]) -> Result<usize, DbError> {
+        let orders = frontier.iter()
+            .map(|v| self.op_db.remote_version_to_order(v).ok_or_else(|| DbError::MissingParent(v.clone())))
+            .collect::<Result<Vec<Order>, DbError>>()?;
+        let discarded = self.op_db.prune(&orders)?;
+        self.checkpoint()?;
+        Ok(discarded)
+    }
+
+    /** Bind an agent to the public key its operations are signed with (see signing::Keyring). */
+    pub fn bind_agent(&mut self, agent: &str, key: ed25519_dalek::VerifyingKey) -> Result<(), DbError> {
+        self.op_db.keys.check(agent, &key).map_err(DbError::InvalidOperation)?;
+        self.persist(Record::BindAgent { agent: agent.to_string(), key: key.to_bytes() })?;
+        self.op_db.keys.bind(agent, key).map_err(DbError::InvalidOperation)
+    }
+
+    /**
+     * Add an ownership rule (see ownership::OwnershipRules). Rules are stored, and only apply to
+     * operations added after them.
+     */
+    pub fn add_ownership_rule(&mut self, pattern: &str) -> Result<(), DbError> {
+        OwnershipRules::check_pattern(pattern).map_err(DbError::InvalidOperation)?;
+        if self.op_db.owners.contains(pattern) { return Ok(()); }
+        self.persist(Record::OwnershipRule(pattern.to_string()))?;
+        self.op_db.owners.add(pattern).map_err(DbError::InvalidOperation)
+    }
+
+    /**
+     * Check the versions an operation refers to are all known, and that each document's parents
+     * are in the operation's history. Operations from peers (or from storage) can't be trusted to
+     * be well formed.
+     */
+    fn check_references(&self, op: &RemoteOperation) -> Result<(), DbError> {
+        if op.parents.is_empty() {
+            return Err(DbError::InvalidOperation("Operations must have parents".to_string()));
+        }
+        let parents = op.parents.iter()
+            .map(|v| self.op_db.remote_version_to_order(v).ok_or_else(|| DbError::MissingParent(v.clone())))
+            .collect::<Result<Vec<Order>, DbError>>()?;
+        if let Some(seq) = op.succeeds {
+            let prev = RemoteVersion { agent: op.version.agent.clone(), seq };
+            self.op_db.remote_version_to_order(&prev).ok_or(DbError::MissingParent(prev))?;
+        }
+
+        for doc_op in &op.doc_ops {
+            for v in &doc_op.parents {
+                let p = self.op_db.remote_version_to_order(v).ok_or_else(|| DbError::MissingParent(v.clone()))?;
+                if p != ROOT_ORDER && doc_op_entry(&self.op_db.operation_by_order(p).doc_ops, &doc_op.id).is_none() {
+                    return Err(DbError::InvalidPatch {
+                        key: doc_op.id.clone(),
+                        message: format!("Parent {}/{} is not a version of the document", v.agent, v.seq),
+                    });
+                }
+                // Document history is checked with the operation ancestry index, which is only
+                // right if a document's parents are in its operation's history.
+                if !self.op_db.branch_contains_version(p, &parents) {
+                    return Err(DbError::InvalidOperation(format!(
+                        "Document parent {}/{} of {} is not in the operation's history", v.agent, v.seq, doc_op.id)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Add an operation (from a local write or from a peer) and apply it to the view. Document
+     * values are checked against the schema registry first. If the operation is invalid, nothing
+     * is changed.
+     */
+    pub fn apply_and_advance(&mut self, op: &RemoteOperation) -> Result<Order, DbError> {
+        if let Some(order) = self.op_db.existing(&op.version, &op.hash)? {
+            // We already have this operation.
+            return Ok(order);
+        }
+
+        self.check_references(op)?;
+
+        for doc_op in &op.doc_ops {
+            // Peers might not have the same derivations, so only our own writes are stopped.
+            if op.version.agent == self.agent && self.view.derived.is_output(&doc_op.id) {
+                return Err(DbError::ReadOnly(doc_op.id.clone()));
+            }
+
+            let parents: Vec<Order> = doc_op.parents.iter()
+                .map(|v| self.op_db.remote_version_to_order(v).unwrap())
+                .collect();
+
+            self.view.check_owner(&self.op_db, &doc_op.id, &parents, &doc_op.patch, &op.version)?;
+            let value = self.view.patched_value(&self.op_db, &doc_op.id, &parents, &doc_op.patch, &op.version)
+                .map_err(|message| DbError::InvalidPatch { key: doc_op.id.clone(), message })?;
+            self.schemas.validate(&doc_op.id, &value)?;
+        }
+
+        self.op_db.check_operation(op)?;
+        self.persist(Record::Op(op.clone()))?;
+        let order = self.op_db.add_checked(op);
+        let changes = self.view.apply_forwards(&self.op_db, order);
+        self.notify(&changes);
+
+        if op.version.agent != self.agent {
+            self.unsequenced.extend(op.doc_ops.iter().map(|doc_op| doc_op.id.clone()));
+            self.sequence_pending();
+        }
+        Ok(order)
+    }
+
+    /** Send changes to subscribers. */
+    fn notify(&mut self, changes: &[DocChange]) {
+        if changes.is_empty() { return; }
+
+        let version = encode_versions(&self.branch_versions());
+        let sources = Sources { ops: &self.op_db, view: &self.view, computed: &self.computed };
+        self.subscriptions.notify(&sources, &version, changes);
+    }
+
+    /**
+     * Sequence actions on documents waiting for it. The operation which proposed the actions has
+     * already been applied, so failures are logged and retried after the next remote operation.
+     */
+    fn sequence_pending(&mut self) {
+        for key in std::mem::take(&mut self.unsequenced) {
+            if let Err(e) = self.sequence_actions(&key) {
+                eprintln!("Could not sequence actions on {}: {}", key, e);
+                self.unsequenced.insert(key);
+            }
+        }
+    }
+
+    /** Define (or redefine) a computed view. Subscribers to the view get its new output. */
+    pub(crate) fn register_view(&mut self, name: &str, def: ViewDef) {
+        self.computed.register(name, def);
+        let version = encode_versions(&self.branch_versions());
+        let sources = Sources { ops: &self.op_db, view: &self.view, computed: &self.computed };
+        self.subscriptions.view_changed(&sources, &version, name);
+    }
+
+    /**
+     * If we own the document and it has pending actions proposed by other agents, sequence them.
+     * Actions are applied in the order they were added to the op log, which is a causal order.
+     */
+    fn sequence_actions(&mut self, key: &DocId) -> Result<(), DbError> {
+        let doc = match self.view.merged_value(key) {
+            Ok(DocValue::Owned(doc)) if doc.owner == self.agent => doc,
+            _ => return Ok(()),
+        };
+
+        let mut pending: Vec<(Order, ItemId)> = doc.pending_ids()
+            .filter_map(|id| {
+                let version = RemoteVersion { agent: id.agent.clone(), seq: id.seq };
+                self.op_db.remote_version_to_order(&version).map(|order| (order, id.clone()))
+            })
+            .collect();
+        if pending.is_empty() { return Ok(()); }
+
+        pending.sort();
+        let ids = pending.into_iter().map(|(_, id)| id).collect();
+        self.write_local(key, DocPatch::Sequence(ids)).map(|_| ())
+    }
+
+    /**
+     * Write a new value to a document on top of the current branch. The new value supersedes all
+     * current versions of the document. Replacing the value with DocValue::None deletes the
+     * document.
+     */
+    pub fn write_local(&mut self, key: &DocId, patch: DocPatch) -> Result<Order, DbError> {
+        let agent = self.agent.clone();
+        let succeeds = self.op_db.agent_map.try_to_local(&agent)
+            .and_then(|local| self.op_db.max_seq(local));
+        let seq = match succeeds {
+            None => 0,
+            Some(i) => i + 1
+        };
+        let doc_succeeds: Vec<RemoteVersion> = self.view.get_cloned(key)
+            .iter()
+            .map(|v| v.order)
+            .map(|order| self.op_db.order_to_remote_version(order))
+            .collect();
+        let parents: Vec<RemoteVersion> = self.view.branch.iter()
+            .map(|order| self.op_db.order_to_remote_version(*order))
+            .collect();
+
+        let mut op = RemoteOperation {
+            version: RemoteVersion { agent, seq },
+            succeeds,
+            parents,
+            doc_ops: vec!(RemoteDocOp {
+                id: key.clone(),
+                patch,
+                parents: doc_succeeds
+            }),
+            hash: merkle::ROOT_HASH,
+            signature: vec!(),
+        };
+        self.seal(&mut op);
+
+        self.apply_and_advance(&op)
+    }
+
+    /** Fill in the hash and signature of an operation from our local agent. */
+    fn seal(&self, op: &mut RemoteOperation) {
+        op.hash = self.op_db.hash_op(op).expect("Operation's parent missing in op db");
+        self.key.sign(op);
+    }
+
+    /** The versions at the tip of the current branch, sorted. */
+    pub fn branch_versions(&self) -> Vec<RemoteVersion> {
+        let mut versions: Vec<RemoteVersion> = self.view.branch.iter()
+            .map(|order| self.op_db.order_to_remote_version(*order))
+            .collect();
+        versions.sort();
+        versions
+    }
+
+    /** The versions of the current (possibly conflicting) values of a document, sorted. */
+    pub fn doc_versions(&self, key: &DocId) -> Vec<RemoteVersion> {
+        let mut versions: Vec<RemoteVersion> = self.view.get_cloned(key)
+            .iter()
+            .map(|v| self.op_db.order_to_remote_version(v.order))
+            .collect();
+        versions.sort();
+        versions
     }
 }
 
@@ -52,7 +418,37 @@ fn main() -> io::Result<()> {
     // let mut op_db = OpDb::new();
     // let mut view = ViewDb::new();
 
-    let mut db = MemDb::new();
+    // Our operations are signed with this key. Set it (a base64 encoded ed25519 seed) to keep the
+    // same identity across restarts.
+    let key = std::env::var("BRAID_SIGNING_KEY").ok().map(|seed| LocalKey::from_base64(&seed)
+        .expect("BRAID_SIGNING_KEY must be a base64 encoded 32 byte seed"));
+
+    // Operations are only persisted if we're given somewhere to put them. With a data key (32
+    // bytes, base64 encoded) everything stored is encrypted.
+    let mut db = match std::env::var("BRAID_DATA_DIR") {
+        Ok(dir) => {
+            let data_key = std::env::var("BRAID_DATA_KEY").ok().map(|key| storage::parse_key(&key)
+                .expect("BRAID_DATA_KEY must be a base64 encoded 32 byte key"));
+            MemDb::open(Path::new(&dir), data_key, key)?
+        },
+        Err(_) => key.map_or_else(MemDb::new, MemDb::with_key),
+    };
+
+    // Whether deletes or edits win when they're concurrent: delete-wins or edit-wins (the default).
+    // This must be the same on every peer.
+    if let Ok(policy) = std::env::var("BRAID_DELETE_POLICY") {
+        db.view.delete_policy = DeletePolicy::parse(policy.trim())
+            .expect("BRAID_DELETE_POLICY must be delete-wins or edit-wins");
+    }
+
+    // Extra ownership rules, separated by commas (eg teams/*/:agent/**). These must be the same on
+    // every peer.
+    if let Ok(patterns) = std::env::var("BRAID_OWNERS") {
+        for pattern in patterns.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            db.add_ownership_rule(pattern)
+                .unwrap_or_else(|e| panic!("Invalid ownership rule in BRAID_OWNERS: {}", e));
+        }
+    }
 
     println!("Db: {:?}", db);
     println!("Doc: {:?}", db.view.get_cloned(&"hi".to_string()));
@@ -61,21 +457,27 @@ fn main() -> io::Result<()> {
         agent: ROOT_AGENT_STR.to_string(),
         seq: 0
     };
-    let op = RemoteOperation {
+    let mut op = RemoteOperation {
         version: RemoteVersion {
-            agent: "seph".to_string(),
+            agent: db.agent.clone(),
             seq: 0
         },
         succeeds: None,
         parents: vec!(root_version.clone()),
         doc_ops: vec!(RemoteDocOp {
             id: "hi".to_string(),
-            patch: DocValue::Blob("hi there".as_bytes().to_vec()),
+            patch: DocPatch::Replace(DocValue::Blob {
+                content_type: "text/plain".to_string(),
+                data: "hi there".as_bytes().to_vec()
+            }),
             parents: vec!(root_version.clone())
-        })
+        }),
+        hash: merkle::ROOT_HASH,
+        signature: vec!(),
     };
+    db.seal(&mut op);
 
-    db.apply_and_advance(&op);
+    db.apply_and_advance(&op).unwrap();
     // let order = db.op_db.add_operation(&op);
     // db.view.apply_forwards(&db.op_db, order);
 
@@ -89,5 +491,126 @@ fn main() -> io::Result<()> {
     // println!("Db: {:?}", db);
     // println!("Doc: {:?}", view.get_cloned(&"hi".to_string()));
 
+    // The root key signs capabilities. It can be set (base64 encoded) so capabilities survive
+    // restarts, otherwise we make a new one.
+    if let Ok(key) = std::env::var("BRAID_ROOT_KEY") {
+        let key = base64::decode(key.trim()).expect("BRAID_ROOT_KEY must be base64");
+        db.authority = Authority::new(key);
+    }
+    println!("Agent: {}", db.agent);
+    println!("Root capability: {}", db.authority.mint().encode());
+
     async_std::task::block_on(host(db))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /** A fresh directory for storage. Tests remove it when they're done. */
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("braid-db-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn json(db: &MemDb, key: &str) -> Vec<DocValue> {
+        db.view.get_resolved(&key.to_string()).into_iter().map(|v| v.value).collect()
+    }
+
+    fn root() -> RemoteVersion {
+        RemoteVersion { agent: ROOT_AGENT_STR.to_string(), seq: 0 }
+    }
+
+    /** A signed operation from another agent, replacing one document. */
+    fn peer_op(db: &MemDb, key: &LocalKey, seq: Seq, parents: Vec<RemoteVersion>, id: &str,
+               doc_parents: Vec<RemoteVersion>, value: serde_json::Value) -> RemoteOperation {
+        let mut op = RemoteOperation {
+            version: RemoteVersion { agent: signing::agent_name(&key.public_key()), seq },
+            succeeds: seq.checked_sub(1),
+            parents,
+            doc_ops: vec!(RemoteDocOp {
+                id: id.to_string(),
+                patch: DocPatch::Replace(DocValue::Json(value)),
+                parents: doc_parents,
+            }),
+            hash: merkle::ROOT_HASH,
+            signature: vec!(),
+        };
+        op.hash = db.op_db.hash_op(&op).unwrap();
+        key.sign(&mut op);
+        op
+    }
+
+    #[test]
+    fn doc_parents_outside_history_rejected() {
+        let mut db = MemDb::new();
+        let peer = LocalKey::default();
+        db.bind_agent(&signing::agent_name(&peer.public_key()), peer.public_key()).unwrap();
+
+        let key = "x".to_string();
+        db.write_local(&key, DocPatch::Replace(DocValue::Json(serde_json::json!(1)))).unwrap();
+        let ours = db.doc_versions(&key);
+
+        // The peer's operation is concurrent with ours, so it can't name ours as a parent of x.
+        let bad = peer_op(&db, &peer, 0, vec!(root()), &key, ours, serde_json::json!(2));
+        assert!(matches!(db.apply_and_advance(&bad), Err(DbError::InvalidOperation(_))));
+
+        // Concurrent edits which don't claim to have seen ours conflict as usual.
+        let good = peer_op(&db, &peer, 0, vec!(root()), &key, vec!(root()), serde_json::json!(3));
+        db.apply_and_advance(&good).unwrap();
+        assert_eq!(db.doc_versions(&key).len(), 2);
+        db.write_local(&key, DocPatch::Replace(DocValue::Json(serde_json::json!(4)))).unwrap();
+        assert_eq!(db.doc_versions(&key).len(), 1);
+    }
+
+    #[test]
+    fn reopen_with_stored_key() {
+        let dir = temp_dir("stored-key");
+        let data_key = Some([9; 32]);
+        let key = "x".to_string();
+        let mut db = MemDb::open(&dir, data_key, Some(LocalKey::from_seed([3; 32]))).unwrap();
+        db.write_local(&key, DocPatch::Replace(DocValue::Json(serde_json::json!(1)))).unwrap();
+        drop(db);
+
+        // Without the key we wrote with, we get the one kept in storage. Our old operations still
+        // have to check out.
+        let db = MemDb::open(&dir, data_key, None).unwrap();
+        assert_eq!(json(&db, "x"), vec!(DocValue::Json(serde_json::json!(1))));
+        drop(db);
+        assert!(MemDb::open(&dir, Some([8; 32]), None).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prune_and_reopen() {
+        let dir = temp_dir("prune");
+        let mut db = MemDb::open(&dir, None, Some(LocalKey::from_seed([4; 32]))).unwrap();
+        let (x, c) = ("x".to_string(), "c".to_string());
+        let mut orders = vec!();
+        for n in 0..3 {
+            orders.push(db.write_local(&x, DocPatch::Replace(DocValue::Json(serde_json::json!(n)))).unwrap());
+            db.write_local(&c, DocPatch::Counter(2)).unwrap();
+        }
+        let (x_before, c_before) = (json(&db, "x"), json(&db, "c"));
+
+        let frontier = db.branch_versions();
+        assert!(db.prune(&frontier).unwrap() > 0);
+        assert_eq!((json(&db, "x"), json(&db, "c")), (x_before.clone(), c_before.clone()));
+
+        // Old versions can't be read or sent to peers any more. The current ones can.
+        let old = orders[0];
+        assert!(matches!(db.op_db.doc_value_at(old, &x), Err(DbError::HistoryPruned(_))));
+        assert!(matches!(db.op_db.remote_operation(old), Err(DbError::HistoryPruned(_))));
+        assert!(db.op_db.doc_value_at(orders[2], &x).is_ok());
+        drop(db);
+
+        let mut db = MemDb::open(&dir, None, None).unwrap();
+        assert_eq!((json(&db, "x"), json(&db, "c")), (x_before, c_before));
+        assert!(matches!(db.op_db.remote_operation(old), Err(DbError::HistoryPruned(_))));
+        // Pruned counters are snapshots, so we can keep adding to them.
+        db.write_local(&c, DocPatch::Counter(1)).unwrap();
+        assert_eq!(db.view.get_resolved(&c)[0].value.to_json(), serde_json::json!(7));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
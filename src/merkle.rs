@@ -0,0 +1,132 @@
+use crate::types::*;
+use crate::signing::signed_bytes;
+use sha2::{Digest, Sha256};
+
+/** The hash of the root version, which every operation descends from. */
+pub const ROOT_HASH: OpHash = [0; 32];
+
+/**
+ * Hash an operation. The hash covers the operation's content (the same canonical encoding which
+ * is signed) and the hashes of its parents, so the op graph is a Merkle DAG: knowing an
+ * operation's hash pins down its entire history. Two peers which both have an operation with the
+ * same hash have identical histories up to that operation.
+ */
+pub(crate) fn hash_op(op: &RemoteOperation, parent_hashes: &[OpHash]) -> OpHash {
+    let mut hasher = Sha256::new();
+    hasher.update(signed_bytes(op));
+    for hash in parent_hashes {
+        hasher.update(hash);
+    }
+    hasher.finalize().into()
+}
+
+/** Hash a set of heads, so peers can check if they're at the same version with one comparison. */
+pub(crate) fn frontier_hash(heads: &[OpHash]) -> OpHash {
+    let mut heads = heads.to_vec();
+    heads.sort_unstable();
+    let mut hasher = Sha256::new();
+    for hash in heads {
+        hasher.update(hash);
+    }
+    hasher.finalize().into()
+}
+
+pub fn to_hex(hash: &OpHash) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> Option<OpHash> {
+    if s.len() != 64 || !s.is_ascii() { return None; }
+    let mut hash = [0; 32];
+    for (i, b) in hash.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+/** Parse a '.' separated list of hex hashes. */
+pub fn from_hex_list(s: &str) -> Option<Vec<OpHash>> {
+    s.split('.').filter(|h| !h.is_empty()).map(from_hex).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DbError;
+    use crate::op_db::OpDb;
+    use crate::signing::{self, LocalKey};
+    use crate::version::ROOT_AGENT_STR;
+
+    fn root() -> RemoteVersion {
+        RemoteVersion { agent: ROOT_AGENT_STR.to_string(), seq: 0 }
+    }
+
+    /** A sealed operation setting document a, on top of parents. */
+    fn op(op_db: &OpDb, key: &LocalKey, seq: Seq, parents: Vec<RemoteVersion>, value: i64) -> RemoteOperation {
+        let mut op = RemoteOperation {
+            version: RemoteVersion { agent: signing::agent_name(&key.public_key()), seq },
+            succeeds: seq.checked_sub(1),
+            parents: parents.clone(),
+            doc_ops: vec!(RemoteDocOp {
+                id: "a".to_string(),
+                patch: DocPatch::Replace(DocValue::Json(serde_json::json!(value))),
+                parents,
+            }),
+            hash: ROOT_HASH,
+            signature: vec!(),
+        };
+        op.hash = op_db.hash_op(&op).unwrap();
+        key.sign(&mut op);
+        op
+    }
+
+    fn op_db(key: &LocalKey) -> OpDb {
+        let mut op_db = OpDb::default();
+        op_db.keys.bind(&signing::agent_name(&key.public_key()), key.public_key()).unwrap();
+        op_db
+    }
+
+    #[test]
+    fn hashes_cover_history() {
+        let key = LocalKey::from_seed([1; 32]);
+        let op_db = op_db(&key);
+        let op = op(&op_db, &key, 0, vec!(root()), 1);
+
+        assert_eq!(hash_op(&op, &[ROOT_HASH]), op.hash);
+        assert_ne!(hash_op(&op, &[[1; 32]]), op.hash);
+        assert_eq!(frontier_hash(&[[1; 32], [2; 32]]), frontier_hash(&[[2; 32], [1; 32]]));
+    }
+
+    #[test]
+    fn hash_mismatch_rejected() {
+        let key = LocalKey::from_seed([1; 32]);
+        let mut op_db = op_db(&key);
+
+        let mut bad = op(&op_db, &key, 0, vec!(root()), 1);
+        // Signatures don't cover the hash, so this is only caught by checking the hash.
+        bad.hash = [1; 32];
+        assert!(matches!(op_db.add_operation(&bad), Err(DbError::BadHash(_))));
+    }
+
+    #[test]
+    fn equivocation_rejected() {
+        let key = LocalKey::from_seed([1; 32]);
+        let mut op_db = op_db(&key);
+
+        let first = op(&op_db, &key, 0, vec!(root()), 1);
+        op_db.add_operation(&first).unwrap();
+        // Adding the same operation again is fine.
+        op_db.add_operation(&first).unwrap();
+
+        let other = op(&op_db, &key, 0, vec!(root()), 2);
+        assert!(matches!(op_db.add_operation(&other), Err(DbError::Equivocation(_))));
+    }
+
+    #[test]
+    fn hex() {
+        let hash: OpHash = core::array::from_fn(|i| i as u8 * 7);
+        assert_eq!(from_hex(&to_hex(&hash)), Some(hash));
+        assert_eq!(from_hex_list(&format!("{}.{}", to_hex(&hash), to_hex(&ROOT_HASH))), Some(vec!(hash, ROOT_HASH)));
+        assert_eq!(from_hex("zz"), None);
+    }
+}
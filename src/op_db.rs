@@ -1,7 +1,13 @@
 use crate::types::*;
-use crate::version::AgentMap;
+use crate::version::{AgentMap, VersionRuns};
 use std::collections::{BTreeMap, BTreeSet};
 use crate::{ROOT_ORDER, ROOT_AGENT, ROOT_VERSION, DEEP_CHECK, doc_op_entry};
+use crate::patch::merge_values;
+use crate::signing::Keyring;
+use crate::ownership::OwnershipRules;
+use crate::error::DbError;
+use crate::merkle::{self, ROOT_HASH};
+use crate::ancestry::Ancestry;
 
 
 #[derive(Debug)]
@@ -15,11 +21,20 @@ pub struct OpDb {
 
     // Ugh, I can't use this because btreemap has no way to
     // version_to_order: BTreeMap<RemoteVersion, ()>,
-    version_to_order: BTreeMap<LocalVersion, Order>,
+    version_to_order: VersionRuns,
+    hash_to_order: BTreeMap<OpHash, Order>,
     // map: BTreeMap<Vec<u8>, Vec<u8>>
 
     // For easy syncing. This only moves forward!
     frontier: Vec<Order>,
+
+    /** Version vectors for fast ancestry checks. */
+    ancestry: Ancestry,
+
+    /** The public keys operations are checked against. */
+    pub(crate) keys: Keyring,
+    /** Which agents can write which documents. */
+    pub(crate) owners: OwnershipRules,
 }
 
 
@@ -27,34 +42,25 @@ impl Default for OpDb {
     fn default() -> Self {
         OpDb {
             agent_map: AgentMap::new(),
-            version_to_order: BTreeMap::new(),
+            version_to_order: VersionRuns::default(),
+            hash_to_order: BTreeMap::new(),
             ops: Vec::new(),
-            frontier: vec!(ROOT_ORDER)
+            frontier: vec!(ROOT_ORDER),
+            ancestry: Ancestry::default(),
+            keys: Keyring::default(),
+            owners: OwnershipRules::default(),
         }
     }
 }
 
-fn entry_before<'a, K: Ord, V>(map: &'a BTreeMap<K, V>, key: &K) -> Option<&'a K> {
-    let mut iter = map.range(..key);
-    iter.next_back().map(|(k, _)| k)
-}
-
 
 impl OpDb {
-    pub(crate) fn new() -> Self {
-        Self::default()
-    }
-
     /**
      * Gets the max known sequence number for the specified agent. None if the
      * agent is not known in the database.
      */
     pub(crate) fn max_seq(&self, agent: Agent) -> Option<Seq> {
-        let end = LocalVersion {agent, seq: Seq::MAX};
-        entry_before(&self.version_to_order, &end)
-            .and_then(|v| {
-                if v.agent == agent {Some(v.seq)} else {None}
-            })
+        self.version_to_order.max_seq(agent)
     }
 
     /** Fetch the operation with the specified order */
@@ -63,16 +69,10 @@ impl OpDb {
         &self.ops[order as usize]
     }
 
-    /** Fetch the operation with the specified remote version */
-    pub(crate) fn operation_by_version(&self, version: &LocalVersion) -> Option<&LocalOperation> {
-        self.version_to_order(version)
-            .map(|order| self.operation_by_order(order))
-    }
-
     pub(crate) fn version_to_order(&self, version: &LocalVersion) -> Option<Order> {
         if version.agent == ROOT_AGENT { Some(ROOT_ORDER) }
         else {
-            self.version_to_order.get(version).cloned()
+            self.version_to_order.get(version)
         }
     }
 
@@ -82,8 +82,8 @@ impl OpDb {
     }
 
     pub(crate) fn remote_version_to_order(&self, version: &RemoteVersion) -> Option<Order> {
-        let local = version.try_to_local(&self.agent_map).unwrap();
-        self.version_to_order(&local)
+        version.try_to_local(&self.agent_map)
+            .and_then(|local| self.version_to_order(&local))
     }
 
     pub(crate) fn order_to_version(&self, order: Order) -> &LocalVersion {
@@ -97,6 +97,130 @@ impl OpDb {
         self.order_to_version(order).to_remote(&self.agent_map)
     }
 
+    pub(crate) fn op_hash(&self, order: Order) -> OpHash {
+        if order == ROOT_ORDER { ROOT_HASH }
+        else { self.operation_by_order(order).hash }
+    }
+
+    pub(crate) fn hash_to_order(&self, hash: &OpHash) -> Option<Order> {
+        if *hash == ROOT_HASH { Some(ROOT_ORDER) }
+        else { self.hash_to_order.get(hash).copied() }
+    }
+
+    /**
+     * Look up an operation we might already have. If we have a different operation with the same
+     * version (because its agent equivocated), that's an error.
+     */
+    pub(crate) fn existing(&self, version: &RemoteVersion, hash: &OpHash) -> Result<Option<Order>, DbError> {
+        match self.remote_version_to_order(version) {
+            Some(order) if self.op_hash(order) != *hash => Err(DbError::Equivocation(version.clone())),
+            existing => Ok(existing),
+        }
+    }
+
+    /** Work out the hash an operation should have. None if we're missing any of its parents. */
+    pub(crate) fn hash_op(&self, op: &RemoteOperation) -> Option<OpHash> {
+        let parent_hashes = op.parents.iter()
+            .map(|v| self.remote_version_to_order(v).map(|order| self.op_hash(order)))
+            .collect::<Option<Vec<OpHash>>>()?;
+        Some(merkle::hash_op(op, &parent_hashes))
+    }
+
+    /**
+     * A sample of the hashes of operations we have, for finding where we diverged from a peer.
+     * This is the hashes of our heads, then of operations further and further back in the op log
+     * (1, 2, 4, 8... operations back), so it grows logarithmically with the log.
+     */
+    pub(crate) fn sample_hashes(&self) -> Vec<OpHash> {
+        let mut hashes: Vec<OpHash> = self.frontier.iter().map(|o| self.op_hash(*o)).collect();
+        let mut back = 1;
+        while back <= self.ops.len() {
+            let hash = self.ops[self.ops.len() - back].hash;
+            if !hashes.contains(&hash) { hashes.push(hash); }
+            back *= 2;
+        }
+        hashes
+    }
+
+    /**
+     * Find where we diverged from a peer, given the hashes of some operations the peer has (eg its
+     * sample_hashes). Returns the newest of those operations which we also have. Because hashes
+     * cover history, we share everything before them too. Unknown hashes are ignored.
+     */
+    pub(crate) fn common_versions(&self, hashes: &[OpHash]) -> Vec<Order> {
+        let mut known: Vec<Order> = hashes.iter().filter_map(|h| self.hash_to_order(h)).collect();
+        known.sort_unstable();
+        known.dedup();
+
+        let common: Vec<Order> = known.iter().copied()
+            .filter(|&o| !known.iter().any(|&other| other != o && self.branch_contains_version(o, &[other])))
+            .collect();
+        if common.is_empty() { vec!(ROOT_ORDER) } else { common }
+    }
+
+    /** The hash of our frontier (see merkle::frontier_hash). */
+    pub(crate) fn frontier_hash(&self) -> OpHash {
+        let heads: Vec<OpHash> = self.frontier.iter().map(|o| self.op_hash(*o)).collect();
+        merkle::frontier_hash(&heads)
+    }
+
+    pub(crate) fn frontier(&self) -> &[Order] {
+        &self.frontier
+    }
+
+    /** Fetch the entry for a document in the operation with the specified order */
+    pub(crate) fn doc_op(&self, order: Order, id: &DocId) -> &LocalDocOp {
+        let op = self.operation_by_order(order);
+        doc_op_entry(&op.doc_ops[..], id).expect("Missing doc op entry in operation")
+    }
+
+    /**
+     * Get the value of a document as of the specified document version (an operation which
+     * modified the document). The value is reconstructed by replaying patches on top of the
+     * closest preceding Replace.
+     */
+    pub(crate) fn doc_value_at(&self, order: Order, id: &DocId) -> Result<DocValue, DbError> {
+        if order == ROOT_ORDER { return Ok(DocValue::None); }
+
+        // Patches can have multiple parents (for CRDTs), so this is a depth first traversal of the
+        // document's history which computes each value once all its parents are known.
+        let mut values = BTreeMap::<Order, DocValue>::new();
+        values.insert(ROOT_ORDER, DocValue::None);
+        let mut stack = vec!(order);
+
+        while let Some(&o) = stack.last() {
+            if values.contains_key(&o) {
+                stack.pop();
+                continue;
+            }
+
+            let doc_op = self.doc_op(o, id);
+            let patch = doc_op.patch.as_ref()
+                .ok_or_else(|| DbError::HistoryPruned(self.order_to_remote_version(o)))?;
+            if let DocPatch::Replace(value) = patch {
+                values.insert(o, value.clone());
+                stack.pop();
+                continue;
+            }
+
+            let missing: Vec<Order> = doc_op.parents.iter()
+                .filter(|p| !values.contains_key(p))
+                .copied().collect();
+            if missing.is_empty() {
+                let base = merge_values(doc_op.parents.iter().map(|p| values[p].clone()).collect())
+                    .expect("Invalid patch in op db");
+                let value = patch.apply(&base, &self.order_to_remote_version(o))
+                    .expect("Invalid patch in op db");
+                values.insert(o, value);
+                stack.pop();
+            } else {
+                stack.extend(missing);
+            }
+        }
+
+        Ok(values.remove(&order).unwrap())
+    }
+
     // ***** Serious utilities
 
 
@@ -119,8 +243,16 @@ impl OpDb {
         }
 
         // Order matters between these two lines because of how this is used in applyBackwards.
-        if branch.len() == 0 { return false; }
+        if branch.is_empty() { return false; }
         if target == ROOT_ORDER || branch.contains(&target) { return true; }
+        // Operations which haven't been added yet aren't in anything's history.
+        if target as usize >= self.ops.len() { return false; }
+
+        // Document parents must be in their operation's history (see MemDb::check_references),
+        // so a document version's history is part of its operation's history, and document mode
+        // can use the ancestry index too.
+        let version = self.operation_by_order(target).version;
+        if let Some(found) = self.ancestry.branch_contains(&version, branch) { return found; }
 
         // This works is via a DFS from the operation with a higher localOrder looking
         // for the Order of the smaller operation.
@@ -172,16 +304,153 @@ impl OpDb {
         found
     }
 
-    /** Add an operation into the operation database. */
-    pub(crate) fn add_operation(&mut self, op: &RemoteOperation) -> Order {
-        assert!(op.parents.len() > 0, "Operation parents field must not be empty");
-        let local_version = op.version.to_local_mut(&mut self.agent_map);
+    /**
+     * List every operation which modified the specified document, reachable from the named
+     * document versions. Results are in descending order (newest first).
+     */
+    pub(crate) fn doc_history(&self, id: &DocId, heads: &[Order]) -> Vec<Order> {
+        let mut visited = BTreeSet::<Order>::new();
+        let mut queue: Vec<Order> = heads.to_vec();
+
+        while let Some(order) = queue.pop() {
+            if order == ROOT_ORDER || visited.contains(&order) { continue; }
+            visited.insert(order);
 
-        if let Some(&order) = self.version_to_order.get(&local_version) {
+            let op = self.operation_by_order(order);
+            let doc_op = doc_op_entry(&op.doc_ops[..], id)
+                .expect("Missing doc op entry in operation");
+            queue.extend(doc_op.parents.iter());
+        }
+
+        visited.into_iter().rev().collect()
+    }
+
+    /**
+     * Convert an operation back to the form it was sent over the network in. This fails if the
+     * operation has been pruned.
+     */
+    pub(crate) fn remote_operation(&self, order: Order) -> Result<RemoteOperation, DbError> {
+        let op = self.operation_by_order(order);
+        if op.pruned {
+            return Err(DbError::HistoryPruned(op.version.to_remote(&self.agent_map)));
+        }
+        let remote = |orders: &[Order]| orders.iter()
+            .map(|o| self.order_to_remote_version(*o))
+            .collect::<Vec<RemoteVersion>>();
+
+        Ok(RemoteOperation {
+            version: op.version.to_remote(&self.agent_map),
+            succeeds: op.succeeds.map(|o| self.order_to_version(o).seq),
+            parents: remote(&op.parents),
+            doc_ops: op.doc_ops.iter().map(|doc_op| RemoteDocOp {
+                id: doc_op.id.clone(),
+                patch: doc_op.patch.clone().expect("Missing patch in unpruned operation"),
+                parents: remote(&doc_op.parents),
+            }).collect(),
+            hash: op.hash,
+            signature: op.signature.clone(),
+        })
+    }
+
+    /** Convert an operation to the form it's stored in once it has been pruned. */
+    pub(crate) fn pruned_operation(&self, order: Order) -> PrunedOperation {
+        let op = self.operation_by_order(order);
+        let remote = |orders: &[Order]| orders.iter()
+            .map(|o| self.order_to_remote_version(*o))
+            .collect::<Vec<RemoteVersion>>();
+
+        PrunedOperation {
+            version: op.version.to_remote(&self.agent_map),
+            succeeds: op.succeeds.map(|o| self.order_to_version(o).seq),
+            parents: remote(&op.parents),
+            doc_ops: op.doc_ops.iter().map(|doc_op| PrunedDocOp {
+                id: doc_op.id.clone(),
+                patch: doc_op.patch.clone(),
+                parents: remote(&doc_op.parents),
+            }).collect(),
+            hash: op.hash,
+            signature: op.signature.clone(),
+        }
+    }
+
+    /** Every operation in the named branch's history, including the branch itself. */
+    fn history(&self, branch: &[Order]) -> BTreeSet<Order> {
+        let mut known = BTreeSet::<Order>::new();
+        let mut queue: Vec<Order> = branch.to_vec();
+
+        while let Some(order) = queue.pop() {
+            if order == ROOT_ORDER || known.contains(&order) { continue; }
+            known.insert(order);
+            queue.extend(self.operation_by_order(order).parents.iter());
+        }
+        known
+    }
+
+    /**
+     * List the operations which aren't in the named branch, in order. Applying them in this order
+     * brings a peer at that branch up to date.
+     */
+    pub(crate) fn ops_since(&self, branch: &[Order]) -> Vec<Order> {
+        let known = self.history(branch);
+        (0..self.ops.len() as Order).filter(|o| !known.contains(o)).collect()
+    }
+
+    /**
+     * Add an operation into the operation database. Operations must be signed by their agent, and
+     * can only modify documents the agent is allowed to write (see OwnershipRules). Otherwise the
+     * operation is rejected.
+     */
+    pub(crate) fn add_operation(&mut self, op: &RemoteOperation) -> Result<Order, DbError> {
+        assert!(!op.parents.is_empty(), "Operation parents field must not be empty");
+        if let Some(order) = self.existing(&op.version, &op.hash)? {
             // The operation is already in the database.
-            return order;
+            return Ok(order);
+        }
+
+        self.check_operation(op)?;
+        Ok(self.add_checked(op))
+    }
+
+    /**
+     * Check a new operation's signature, hash and ownership, without adding it. Its parents must
+     * already be in the database.
+     */
+    pub(crate) fn check_operation(&self, op: &RemoteOperation) -> Result<(), DbError> {
+        self.keys.verify(op)?;
+        let hash = self.hash_op(op).expect("Operation's parent missing in op db");
+        if hash != op.hash { return Err(DbError::BadHash(op.version.clone())); }
+        for doc_op in &op.doc_ops {
+            self.owners.check(&op.version.agent, &doc_op.id)
+                .map_err(|owner| DbError::NotOwner { key: doc_op.id.clone(), owner })?;
+        }
+        Ok(())
+    }
+
+    /** Add a new operation which has passed check_operation. */
+    pub(crate) fn add_checked(&mut self, op: &RemoteOperation) -> Order {
+        self.insert(&PrunedOperation::from(op), false)
+    }
+
+    /**
+     * Add a pruned operation read back from storage. These can't be verified, so they must only
+     * come from our own storage.
+     */
+    pub(crate) fn add_pruned(&mut self, op: &PrunedOperation) -> Result<Order, DbError> {
+        if let Some(order) = self.existing(&op.version, &op.hash)? {
+            return Ok(order);
         }
 
+        let referenced = op.parents.iter()
+            .chain(op.doc_ops.iter().flat_map(|doc_op| doc_op.parents.iter()));
+        for v in referenced {
+            self.remote_version_to_order(v).ok_or_else(|| DbError::MissingParent(v.clone()))?;
+        }
+        Ok(self.insert(op, true))
+    }
+
+    fn insert(&mut self, op: &PrunedOperation, pruned: bool) -> Order {
+        let local_version = op.version.to_local_mut(&mut self.agent_map);
+
         // Check that all of this operation's parents are already present.
         // println!("inserting {:?}", op);
         let parent_orders = op.parents.iter().map(|v| {
@@ -199,26 +468,92 @@ impl OpDb {
             doc_ops: op.doc_ops.iter().map(|doc_op| LocalDocOp {
                 id: doc_op.id.clone(),
                 parents: doc_op.parents.iter().map(|v| {
-                    self.remote_version_to_order_mut(&v).expect("Docop parent missing")
+                    self.remote_version_to_order_mut(v).expect("Docop parent missing")
                 }).collect(),
                 patch: doc_op.patch.clone(),
             }).collect(),
             succeeds: op.succeeds.map(|seq| self.version_to_order(&LocalVersion {
                 agent: local_version.agent,
                 seq
-            }).expect("Predecessor missing in database"))
+            }).expect("Predecessor missing in database")),
+            hash: op.hash,
+            signature: op.signature.clone(),
+            pruned,
         };
 
         // TODO: Avoid allocation here.
         self.frontier = self.advance_branch_by_op(&self.frontier[..], &local_op);
 
+        let succeeds_seq = local_op.succeeds.map(|o| self.order_to_version(o).seq);
+        self.ancestry.add(&local_op, succeeds_seq, self.max_seq(local_version.agent));
+
         // And save the new operation in the store.
         self.ops.push(local_op);
         self.version_to_order.insert(local_version, new_order);
+        self.hash_to_order.insert(op.hash, new_order);
 
         new_order
     }
 
+    /**
+     * Discard history which every peer has. The frontier must be a version every peer has
+     * acknowledged, so nobody will send us operations written against anything older.
+     *
+     * Patches of document versions in the frontier's history are discarded, except for versions
+     * which are still needed: each document's versions as of the frontier, and versions newer
+     * operations were written against. Those are collapsed to snapshots of their values. The op
+     * graph (versions, parents and hashes) is kept, so sync can still find where peers diverged.
+     *
+     * Returns how many patches were discarded. If the frontier needs history which has already
+     * been discarded, nothing is changed.
+     */
+    pub(crate) fn prune(&mut self, frontier: &[Order]) -> Result<usize, DbError> {
+        let history = self.history(frontier);
+
+        // Document versions superseded within the history, and versions still needed by newer
+        // operations.
+        let mut superseded = BTreeSet::<(Order, DocId)>::new();
+        let mut needed = BTreeSet::<(Order, DocId)>::new();
+        for op in &self.ops {
+            for doc_op in &op.doc_ops {
+                let set = if history.contains(&op.order) { &mut superseded } else { &mut needed };
+                set.extend(doc_op.parents.iter().map(|p| (*p, doc_op.id.clone())));
+            }
+        }
+
+        // Snapshots are made before anything is discarded, because they need the old patches.
+        let mut snapshots = vec!();
+        let mut discarded = vec!();
+        for &order in &history {
+            for doc_op in &self.operation_by_order(order).doc_ops {
+                let version = (order, doc_op.id.clone());
+                let still_needed = !superseded.contains(&version) || needed.contains(&version);
+                match &doc_op.patch {
+                    None => {}, // Already discarded.
+                    Some(_) if !still_needed => discarded.push(version),
+                    Some(DocPatch::Replace(_)) => {}, // Already a snapshot.
+                    Some(_) => snapshots.push((version, self.doc_value_at(order, &doc_op.id)?)),
+                }
+            }
+        }
+
+        for ((order, id), value) in snapshots {
+            self.set_patch(order, &id, Some(DocPatch::Replace(value)));
+        }
+        for (order, id) in &discarded {
+            self.set_patch(*order, id, None);
+        }
+        Ok(discarded.len())
+    }
+
+    fn set_patch(&mut self, order: Order, id: &DocId, patch: Option<DocPatch>) {
+        let op = &mut self.ops[order as usize];
+        op.pruned = true;
+        op.doc_ops.iter_mut().find(|doc_op| &doc_op.id == id)
+            .expect("Missing doc op entry in operation")
+            .patch = patch;
+    }
+
     // I'm not entirely sure where this function should live.
     // TODO: Consider rewriting this to avoid the allocation.
     pub(crate) fn advance_branch_by_op(&self, branch: &[Order], op: &LocalOperation) -> Vec<Order> {
@@ -0,0 +1,217 @@
+use crate::types::*;
+use crate::rga::ItemId;
+use serde_json::{Value, Map};
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+
+/**
+ * A reducer computes a document's next state from its current state and an action. Returning an
+ * error rejects the action, which is how owned documents enforce invariants.
+ */
+pub type Reducer = fn(state: &Value, action: &Action) -> Result<Value, String>;
+
+/** Reducers are registered here by name. Owned documents name the reducer they use. */
+const REDUCERS: [(&str, Reducer); 1] = [
+    ("ledger", ledger),
+];
+
+pub fn reducer(name: &str) -> Option<Reducer> {
+    REDUCERS.iter().find(|(n, _)| *n == name).map(|(_, r)| *r)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Action {
+    pub name: String,
+    pub args: Value,
+}
+
+/**
+ * A document which is only changed through named actions. One agent owns the document and acts as
+ * its sequencer. Actions written by the owner are applied immediately. Actions written by anyone
+ * else are proposals, which stay pending until the owner sequences them (see
+ * DocPatch::Sequence). The owner applies each proposal with the reducer in turn and either
+ * accepts or rejects it, so every replica ends up with the state the owner computed.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OwnedDoc {
+    pub owner: String,
+    pub reducer: String,
+    state: Value,
+    /** The number of actions the owner has applied. The owner's writes are totally ordered. */
+    applied: u64,
+    /** Proposals waiting for the owner, by the ID of the operation which proposed them. */
+    pending: BTreeMap<ItemId, Action>,
+    /** Proposals the owner has sequenced. Rejected proposals have an error message. */
+    decided: BTreeMap<ItemId, Option<String>>,
+}
+
+impl OwnedDoc {
+    pub fn new(owner: String, reducer: String, state: Value) -> Self {
+        OwnedDoc {
+            owner,
+            reducer,
+            state,
+            applied: 0,
+            pending: BTreeMap::new(),
+            decided: BTreeMap::new(),
+        }
+    }
+
+    pub fn state(&self) -> &Value {
+        &self.state
+    }
+
+    pub fn pending_ids(&self) -> impl Iterator<Item=&ItemId> {
+        self.pending.keys()
+    }
+
+    /** The outcome of a proposal. None if it's still pending, or Some(Err) if it was rejected. */
+    pub fn outcome(&self, id: &ItemId) -> Option<Result<(), &str>> {
+        self.decided.get(id).map(|e| match e {
+            None => Ok(()),
+            Some(e) => Err(e.as_str()),
+        })
+    }
+
+    fn reduce(&self, action: &Action) -> Result<Value, String> {
+        let reducer = reducer(&self.reducer)
+            .ok_or_else(|| format!("Unknown reducer '{}'", self.reducer))?;
+        reducer(&self.state, action)
+    }
+
+    /**
+     * Merge another replica's state into this one. The owner's states form a chain, so we keep
+     * whichever state has had more actions applied.
+     */
+    pub fn merge(&mut self, other: &OwnedDoc) {
+        if other.applied > self.applied {
+            self.state = other.state.clone();
+            self.applied = other.applied;
+        }
+        for (id, outcome) in other.decided.iter() {
+            self.decided.entry(id.clone()).or_insert_with(|| outcome.clone());
+        }
+        for (id, action) in other.pending.iter() {
+            self.pending.entry(id.clone()).or_insert_with(|| action.clone());
+        }
+        let decided = &self.decided;
+        self.pending.retain(|id, _| !decided.contains_key(id));
+    }
+
+    /** Apply an action written by the operation with the specified version. */
+    pub fn apply_action(&mut self, action: &Action, version: &RemoteVersion) -> Result<(), String> {
+        if version.agent == self.owner {
+            self.state = self.reduce(action)?;
+            self.applied += 1;
+        } else {
+            self.pending.insert(ItemId::new(version, 0), action.clone());
+        }
+        Ok(())
+    }
+
+    /** Apply pending proposals in the specified order. Only the owner can do this. */
+    pub fn apply_sequence(&mut self, ids: &[ItemId], version: &RemoteVersion) -> Result<(), String> {
+        if version.agent != self.owner {
+            return Err(format!("Only {} can sequence actions", self.owner));
+        }
+
+        for id in ids {
+            let action = self.pending.remove(id)
+                .ok_or_else(|| format!("No pending action {}/{}", id.agent, id.seq))?;
+            match self.reduce(&action) {
+                Ok(state) => {
+                    self.state = state;
+                    self.applied += 1;
+                    self.decided.insert(id.clone(), None);
+                },
+                Err(e) => { self.decided.insert(id.clone(), Some(e)); },
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Account balances. The state is an object mapping account names to balances, and the actions are
+ * `deposit {to, amount}`, `withdraw {from, amount}` and `transfer {from, to, amount}`. Balances
+ * are u64s, and actions which would take one out of range are rejected.
+ */
+fn ledger(state: &Value, action: &Action) -> Result<Value, String> {
+    let mut balances = match state {
+        Value::Object(balances) => balances.clone(),
+        Value::Null => Map::new(),
+        _ => return Err("Ledger state must be an object".to_string()),
+    };
+
+    let account = |field: &str| -> Result<String, String> {
+        action.args.get(field).and_then(|v| v.as_str()).map(|s| s.to_string())
+            .ok_or_else(|| format!("{} needs a '{}' account", action.name, field))
+    };
+    let amount = action.args.get("amount").and_then(|v| v.as_u64())
+        .filter(|a| *a > 0)
+        .ok_or_else(|| format!("{} needs a positive integer amount", action.name))?;
+
+    let mut adjust = |account: String, credit: bool| -> Result<(), String> {
+        let balance = match balances.get(&account) {
+            None => 0,
+            Some(balance) => balance.as_u64()
+                .ok_or_else(|| format!("Balance of '{}' is not a non-negative integer", account))?,
+        };
+        let balance = if credit {
+            balance.checked_add(amount).ok_or_else(|| format!("Balance of '{}' is too large", account))?
+        } else {
+            balance.checked_sub(amount).ok_or_else(|| format!("Insufficient funds in '{}'", account))?
+        };
+        balances.insert(account, Value::from(balance));
+        Ok(())
+    };
+
+    match action.name.as_str() {
+        "deposit" => adjust(account("to")?, true)?,
+        "withdraw" => adjust(account("from")?, false)?,
+        "transfer" => {
+            adjust(account("from")?, false)?;
+            adjust(account("to")?, true)?;
+        },
+        name => return Err(format!("Unknown ledger action '{}'", name)),
+    }
+    Ok(Value::Object(balances))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn action(name: &str, args: Value) -> Action {
+        Action { name: name.to_string(), args }
+    }
+
+    #[test]
+    fn ledger_rejects_overflow() {
+        let deposit = action("deposit", json!({ "to": "a", "amount": u64::MAX }));
+        let state = ledger(&Value::Null, &deposit).unwrap();
+        assert_eq!(state, json!({ "a": u64::MAX }));
+        assert!(ledger(&state, &deposit).is_err());
+
+        let transfer = action("transfer", json!({ "from": "a", "to": "b", "amount": 1 }));
+        let full = json!({ "a": 1, "b": u64::MAX });
+        assert!(ledger(&full, &transfer).is_err());
+    }
+
+    #[test]
+    fn ledger_rejects_overdraft() {
+        let withdraw = action("withdraw", json!({ "from": "a", "amount": 3 }));
+        assert_eq!(ledger(&json!({ "a": 3 }), &withdraw).unwrap(), json!({ "a": 0 }));
+        assert!(ledger(&json!({ "a": 2 }), &withdraw).is_err());
+        assert!(ledger(&Value::Null, &withdraw).is_err());
+    }
+
+    #[test]
+    fn ledger_rejects_invalid_balances() {
+        let deposit = action("deposit", json!({ "to": "a", "amount": 1 }));
+        assert!(ledger(&json!({ "a": -5 }), &deposit).is_err());
+        assert!(ledger(&json!({ "a": "lots" }), &deposit).is_err());
+        assert!(ledger(&json!({ "a": 1.5 }), &deposit).is_err());
+    }
+}
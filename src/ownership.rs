@@ -0,0 +1,151 @@
+use crate::types::*;
+
+/** The segment of an ownership pattern which names the owning agent. */
+const AGENT_SEGMENT: &str = ":agent";
+
+/**
+ * Per-document write ownership. Each rule is a key pattern with an `:agent` segment, and
+ * documents matching it can only be written by the agent named in that segment. Other segments
+ * are matched like schema patterns (see schema::glob_matches). By default everything under
+ * `users/<agent>` belongs to that agent.
+ *
+ * Rules are checked when operations are added, so they apply to operations from peers as well as
+ * local writes. Rules are configured at startup (BRAID_OWNERS) and stored with the database.
+ * Every peer needs the same rules, or they'll disagree about which operations are valid.
+ */
+#[derive(Debug)]
+pub struct OwnershipRules {
+    patterns: Vec<String>,
+}
+
+impl Default for OwnershipRules {
+    fn default() -> Self {
+        OwnershipRules { patterns: vec!("users/:agent/**".to_string()) }
+    }
+}
+
+/** The agent a pattern gives ownership of a key to, if the key matches the pattern. */
+fn match_owner<'a>(pattern: &str, key: &'a str) -> Option<&'a str> {
+    let mut key_segments = key.split('/');
+    let mut owner = None;
+    for p in pattern.split('/') {
+        if p == "**" { return owner; }
+
+        let k = key_segments.next()?;
+        if p == AGENT_SEGMENT {
+            owner = Some(k);
+        } else if p != "*" && p != k {
+            return None;
+        }
+    }
+
+    if key_segments.next().is_none() { owner } else { None }
+}
+
+impl OwnershipRules {
+    /** Check a pattern is a valid rule. */
+    pub fn check_pattern(pattern: &str) -> Result<(), String> {
+        let segments: Vec<&str> = pattern.split('/').collect();
+        if segments.iter().filter(|s| **s == AGENT_SEGMENT).count() != 1 {
+            return Err(format!("Pattern must have exactly one {} segment", AGENT_SEGMENT));
+        }
+        if segments[..segments.len() - 1].contains(&"**") {
+            return Err("** can only be used at the end of a pattern".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn add(&mut self, pattern: &str) -> Result<(), String> {
+        Self::check_pattern(pattern)?;
+        if !self.contains(pattern) {
+            self.patterns.push(pattern.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, pattern: &str) -> bool {
+        self.patterns.iter().any(|p| p == pattern)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&str> {
+        self.patterns.iter().map(|p| p.as_str())
+    }
+
+    /** The agents allowed to write a document. If this is empty, anyone can. */
+    pub fn owners<'a>(&self, key: &'a DocId) -> Vec<&'a str> {
+        self.patterns.iter().filter_map(|p| match_owner(p, key)).collect()
+    }
+
+    /** Check an agent may write a document. Returns the document's owner if it can't. */
+    pub fn check(&self, agent: &str, key: &DocId) -> Result<(), String> {
+        match self.owners(key).into_iter().find(|owner| *owner != agent) {
+            Some(owner) => Err(owner.to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DbError;
+    use crate::merkle::ROOT_HASH;
+    use crate::op_db::OpDb;
+    use crate::signing::{self, LocalKey};
+    use crate::version::ROOT_AGENT_STR;
+
+    #[test]
+    fn patterns() {
+        let mut rules = OwnershipRules::default();
+        rules.add("teams/*/:agent/**").unwrap();
+        let key = |k: &str| k.to_string();
+
+        assert_eq!(rules.owners(&key("users/alice/notes")), vec!("alice"));
+        assert_eq!(rules.owners(&key("teams/red/bob/x/y")), vec!("bob"));
+        assert!(rules.owners(&key("teams/red")).is_empty());
+        assert!(rules.owners(&key("public/alice")).is_empty());
+
+        assert_eq!(rules.check("alice", &key("users/alice/notes")), Ok(()));
+        assert_eq!(rules.check("bob", &key("users/alice/notes")), Err("alice".to_string()));
+        assert_eq!(rules.check("bob", &key("public/x")), Ok(()));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert!(OwnershipRules::check_pattern("users/*").is_err());
+        assert!(OwnershipRules::check_pattern(":agent/:agent").is_err());
+        assert!(OwnershipRules::check_pattern("**/:agent").is_err());
+        assert!(OwnershipRules::check_pattern("a/:agent/**").is_ok());
+    }
+
+    #[test]
+    fn foreign_agents_rejected() {
+        let key = LocalKey::from_seed([1; 32]);
+        let agent = signing::agent_name(&key.public_key());
+        let mut op_db = OpDb::default();
+        op_db.keys.bind(&agent, key.public_key()).unwrap();
+
+        let write = |op_db: &mut OpDb, seq: Seq, id: &str| {
+            let root = RemoteVersion { agent: ROOT_AGENT_STR.to_string(), seq: 0 };
+            let mut op = RemoteOperation {
+                version: RemoteVersion { agent: agent.clone(), seq },
+                succeeds: seq.checked_sub(1),
+                parents: vec!(root.clone()),
+                doc_ops: vec!(RemoteDocOp {
+                    id: id.to_string(),
+                    patch: DocPatch::Replace(DocValue::Json(serde_json::json!(1))),
+                    parents: vec!(root),
+                }),
+                hash: ROOT_HASH,
+                signature: vec!(),
+            };
+            op.hash = op_db.hash_op(&op).unwrap();
+            key.sign(&mut op);
+            op_db.add_operation(&op)
+        };
+
+        write(&mut op_db, 0, &format!("users/{}/x", agent)).unwrap();
+        let result = write(&mut op_db, 1, "users/someone-else/x");
+        assert!(matches!(result, Err(DbError::NotOwner { owner, .. }) if owner == "someone-else"));
+    }
+}
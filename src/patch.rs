@@ -0,0 +1,350 @@
+use crate::types::*;
+use crate::text_crdt::TextDoc;
+use crate::json_crdt::JsonDoc;
+use crate::counter_crdt::Counter;
+use crate::set_crdt::OrSet;
+use serde_json::{Value, Map};
+use serde::{Serialize, Deserialize};
+
+/** One operation in an RFC 6902 JSON patch. Paths are JSON pointers (RFC 6901). */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JsonPatchOp {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+fn parse_pointer(pointer: &str) -> Result<Vec<String>, String> {
+    if pointer.is_empty() { return Ok(vec!()); }
+    if !pointer.starts_with('/') {
+        return Err(format!("Invalid JSON pointer '{}'", pointer));
+    }
+
+    Ok(pointer[1..].split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+fn array_index(token: &str, len: usize, allow_end: bool) -> Result<usize, String> {
+    if allow_end && token == "-" { return Ok(len); }
+
+    let idx = token.parse::<usize>()
+        .map_err(|_| format!("Invalid array index '{}'", token))?;
+    if idx > len || (!allow_end && idx == len) {
+        return Err(format!("Array index {} out of bounds", idx));
+    }
+    Ok(idx)
+}
+
+fn lookup<'a>(doc: &'a Value, pointer: &str) -> Result<&'a Value, String> {
+    doc.pointer(pointer).ok_or_else(|| format!("No value at '{}'", pointer))
+}
+
+/** Find the container holding the value at path. Returns the container and the last token. */
+fn parent_mut<'a>(doc: &'a mut Value, path: &str) -> Result<(&'a mut Value, String), String> {
+    let mut tokens = parse_pointer(path)?;
+    let last = tokens.pop().ok_or_else(|| "Path must not be the document root".to_string())?;
+
+    let mut parent = String::new();
+    for t in tokens {
+        parent.push('/');
+        parent.push_str(&t.replace('~', "~0").replace('/', "~1"));
+    }
+
+    let container = doc.pointer_mut(&parent)
+        .ok_or_else(|| format!("No value at '{}'", parent))?;
+    Ok((container, last))
+}
+
+fn add(doc: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    if path.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+
+    let (container, last) = parent_mut(doc, path)?;
+    match container {
+        Value::Object(obj) => { obj.insert(last, value); },
+        Value::Array(arr) => {
+            let idx = array_index(&last, arr.len(), true)?;
+            arr.insert(idx, value);
+        },
+        _ => return Err(format!("Cannot add a child to a scalar at '{}'", path)),
+    }
+    Ok(())
+}
+
+fn remove(doc: &mut Value, path: &str) -> Result<Value, String> {
+    let (container, last) = parent_mut(doc, path)?;
+    match container {
+        Value::Object(obj) => obj.remove(&last)
+            .ok_or_else(|| format!("No value at '{}'", path)),
+        Value::Array(arr) => {
+            let idx = array_index(&last, arr.len(), false)?;
+            Ok(arr.remove(idx))
+        },
+        _ => Err(format!("No value at '{}'", path)),
+    }
+}
+
+impl JsonPatchOp {
+    /** Parse a JSON patch document (a list of operations). */
+    pub fn parse_list(json: &Value) -> Result<Vec<JsonPatchOp>, String> {
+        let ops = json.as_array().ok_or_else(|| "JSON patch must be a list".to_string())?;
+        ops.iter().map(JsonPatchOp::parse).collect()
+    }
+
+    fn parse(json: &Value) -> Result<JsonPatchOp, String> {
+        let field = |name: &str| -> Result<String, String> {
+            json.get(name)
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string())
+                .ok_or_else(|| format!("JSON patch operation missing '{}'", name))
+        };
+        let value = || -> Result<Value, String> {
+            json.get("value").cloned()
+                .ok_or_else(|| "JSON patch operation missing 'value'".to_string())
+        };
+
+        Ok(match field("op")?.as_str() {
+            "add" => JsonPatchOp::Add { path: field("path")?, value: value()? },
+            "remove" => JsonPatchOp::Remove { path: field("path")? },
+            "replace" => JsonPatchOp::Replace { path: field("path")?, value: value()? },
+            "move" => JsonPatchOp::Move { from: field("from")?, path: field("path")? },
+            "copy" => JsonPatchOp::Copy { from: field("from")?, path: field("path")? },
+            "test" => JsonPatchOp::Test { path: field("path")?, value: value()? },
+            op => return Err(format!("Unknown JSON patch operation '{}'", op)),
+        })
+    }
+
+    pub(crate) fn apply(&self, doc: &mut Value) -> Result<(), String> {
+        match self {
+            JsonPatchOp::Add { path, value } => add(doc, path, value.clone()),
+            JsonPatchOp::Remove { path } => remove(doc, path).map(|_| ()),
+            JsonPatchOp::Replace { path, value } => {
+                let target = doc.pointer_mut(path)
+                    .ok_or_else(|| format!("No value at '{}'", path))?;
+                *target = value.clone();
+                Ok(())
+            },
+            JsonPatchOp::Move { from, path } => {
+                if from == path { return Ok(()); }
+                if path.starts_with(&format!("{}/", from)) {
+                    return Err(format!("Cannot move '{}' into itself", from));
+                }
+                let value = remove(doc, from)?;
+                add(doc, path, value)
+            },
+            JsonPatchOp::Copy { from, path } => {
+                let value = lookup(doc, from)?.clone();
+                add(doc, path, value)
+            },
+            JsonPatchOp::Test { path, value } => {
+                if lookup(doc, path)? == value { Ok(()) }
+                else { Err(format!("Test failed at '{}'", path)) }
+            },
+        }
+    }
+}
+
+/** RFC 7386. */
+pub(crate) fn merge_patch(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(fields) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+            let obj = target.as_object_mut().unwrap();
+            for (k, v) in fields {
+                if v.is_null() {
+                    obj.remove(k);
+                } else {
+                    merge_patch(obj.entry(k.clone()).or_insert(Value::Null), v);
+                }
+            }
+        },
+        _ => *target = patch.clone(),
+    }
+}
+
+impl DocPatch {
+    /** Replace patches don't depend on the document's previous value. */
+    pub fn is_replace(&self) -> bool {
+        matches!(self, DocPatch::Replace(_))
+    }
+
+    /**
+     * Apply this patch to the value it was written against, returning the new value. The version
+     * is the version of the operation which contains the patch.
+     */
+    pub fn apply(&self, base: &DocValue, version: &RemoteVersion) -> Result<DocValue, String> {
+        match self {
+            DocPatch::Replace(value) => Ok(value.clone()),
+            DocPatch::MergePatch(patch) => {
+                let mut json = match base {
+                    DocValue::Json(json) => json.clone(),
+                    DocValue::None => Value::Null,
+                    _ => return Err("Merge patches can only be applied to JSON documents".to_string()),
+                };
+                merge_patch(&mut json, patch);
+                Ok(DocValue::Json(json))
+            },
+            DocPatch::JsonPatch(ops) => {
+                let mut json = match base {
+                    DocValue::Json(json) => json.clone(),
+                    DocValue::None => Value::Null,
+                    _ => return Err("JSON patches can only be applied to JSON documents".to_string()),
+                };
+                // The patch is atomic. If any operation fails, the whole patch fails.
+                for op in ops {
+                    op.apply(&mut json)?;
+                }
+                Ok(DocValue::Json(json))
+            },
+            DocPatch::Splice { pos, remove, insert } => {
+                let (content_type, data) = match base {
+                    DocValue::Blob { content_type, data } => (content_type.clone(), data),
+                    DocValue::None => ("application/octet-stream".to_string(), &Vec::new()),
+                    _ => return Err("Splices can only be applied to blobs".to_string()),
+                };
+
+                let end = pos.checked_add(*remove).filter(|end| *end <= data.len())
+                    .ok_or_else(|| format!("Splice of {} bytes at {} is out of bounds", remove, pos))?;
+
+                let mut new_data = Vec::with_capacity(data.len() - remove + insert.len());
+                new_data.extend_from_slice(&data[..*pos]);
+                new_data.extend_from_slice(insert);
+                new_data.extend_from_slice(&data[end..]);
+                Ok(DocValue::Blob { content_type, data: new_data })
+            },
+            DocPatch::Text(ops) => {
+                let mut text = match base {
+                    DocValue::Text(text) => text.clone(),
+                    DocValue::None => TextDoc::new(),
+                    _ => return Err("Text edits can only be applied to text documents".to_string()),
+                };
+                text.apply(ops, version)?;
+                Ok(DocValue::Text(text))
+            },
+            DocPatch::JsonCrdt { mode, ops } => {
+                let mut doc = match base {
+                    DocValue::JsonCrdt(doc) if doc.mode == *mode => doc.clone(),
+                    DocValue::None => JsonDoc::new(*mode),
+                    _ => return Err("JSON CRDT edits can only be applied to JSON CRDT documents with the same mode".to_string()),
+                };
+                doc.apply(ops, version)?;
+                Ok(DocValue::JsonCrdt(doc))
+            },
+            DocPatch::Counter(amount) => {
+                let mut counter = match base {
+                    DocValue::Counter(counter) => counter.clone(),
+                    DocValue::None => Counter::new(),
+                    _ => return Err("Increments can only be applied to counters".to_string()),
+                };
+                counter.apply(*amount, version)?;
+                Ok(DocValue::Counter(counter))
+            },
+            DocPatch::Set(ops) => {
+                let mut set = match base {
+                    DocValue::Set(set) => set.clone(),
+                    DocValue::None => OrSet::new(),
+                    _ => return Err("Set operations can only be applied to sets".to_string()),
+                };
+                set.apply(ops, version)?;
+                Ok(DocValue::Set(set))
+            },
+            DocPatch::Action(action) => {
+                let mut doc = match base {
+                    DocValue::Owned(doc) => doc.clone(),
+                    _ => return Err("Actions can only be applied to owned documents".to_string()),
+                };
+                doc.apply_action(action, version)?;
+                Ok(DocValue::Owned(doc))
+            },
+            DocPatch::Sequence(ids) => {
+                let mut doc = match base {
+                    DocValue::Owned(doc) => doc.clone(),
+                    _ => return Err("Actions can only be applied to owned documents".to_string()),
+                };
+                doc.apply_sequence(ids, version)?;
+                Ok(DocValue::Owned(doc))
+            },
+        }
+    }
+}
+
+impl DocValue {
+    /**
+     * CRDT values and owned documents can merge with other values of the same kind. Concurrent
+     * versions of these documents aren't conflicts.
+     */
+    pub fn is_mergeable(&self) -> bool {
+        matches!(self, DocValue::Text(_) | DocValue::JsonCrdt(_) | DocValue::Counter(_) | DocValue::Set(_) | DocValue::Owned(_))
+    }
+
+    /** The JSON form of structured documents. Blobs and deleted documents have none. */
+    pub fn json(&self) -> Option<Value> {
+        match self {
+            DocValue::None | DocValue::Blob { .. } => None,
+            DocValue::Json(json) => Some(json.clone()),
+            DocValue::Text(text) => Some(Value::String(text.to_string())),
+            DocValue::JsonCrdt(doc) => Some(doc.to_json()),
+            DocValue::Counter(counter) => Some(Value::from(counter.value())),
+            DocValue::Set(set) => Some(set.to_json()),
+            DocValue::Owned(doc) => Some(doc.state().clone()),
+        }
+    }
+
+    /** Blobs are embedded in JSON responses as strings if they're valid UTF-8, or base64. */
+    pub fn to_json(&self) -> Value {
+        match self {
+            DocValue::None => Value::Null,
+            DocValue::Blob { data, .. } => match std::str::from_utf8(data) {
+                Ok(s) => serde_json::json!(s),
+                Err(_) => serde_json::json!({ "base64": base64::encode(data) }),
+            },
+            value => value.json().unwrap(),
+        }
+    }
+}
+
+/**
+ * Merge a set of concurrent versions of a document into one value. This fails unless there's only
+ * one value, or every value is mergeable and of the same kind. Empty (None) values are ignored.
+ */
+pub fn merge_values(values: Vec<DocValue>) -> Result<DocValue, String> {
+    let mut values = values.into_iter();
+    let mut result = values.next().unwrap_or(DocValue::None);
+
+    for value in values {
+        result = match (result, value) {
+            (DocValue::None, v) | (v, DocValue::None) if v.is_mergeable() || v == DocValue::None => v,
+            (DocValue::Text(mut a), DocValue::Text(b)) => {
+                a.merge(&b);
+                DocValue::Text(a)
+            },
+            (DocValue::JsonCrdt(mut a), DocValue::JsonCrdt(b)) if a.mode == b.mode => {
+                a.merge(&b)?;
+                DocValue::JsonCrdt(a)
+            },
+            (DocValue::Counter(mut a), DocValue::Counter(b)) => {
+                a.merge(&b);
+                DocValue::Counter(a)
+            },
+            (DocValue::Set(mut a), DocValue::Set(b)) => {
+                a.merge(&b);
+                DocValue::Set(a)
+            },
+            (DocValue::Owned(mut a), DocValue::Owned(b)) if a.owner == b.owner && a.reducer == b.reducer => {
+                a.merge(&b);
+                DocValue::Owned(a)
+            },
+            _ => return Err("Patches must be written against exactly one version of a document".to_string()),
+        }
+    }
+
+    Ok(result)
+}
@@ -0,0 +1,128 @@
+use async_std::channel::Sender;
+use serde_json::{Value, Map};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use crate::subscriptions::frame;
+use crate::capability::{self, Capability, Access};
+
+pub(crate) const DEFAULT_TTL: Duration = Duration::from_secs(30);
+pub(crate) const MAX_TTL: Duration = Duration::from_secs(300);
+/** Presence is for small things like cursors. Bigger state belongs in a document. */
+pub(crate) const MAX_STATE_SIZE: usize = 4096;
+
+#[derive(Debug)]
+struct Entry {
+    state: Value,
+    expires: Instant,
+}
+
+#[derive(Debug)]
+struct Watcher {
+    room: String,
+    /** The client this connection belongs to. */
+    client: String,
+    /** The watcher's capability. Watchers are dropped once it no longer lets them watch. */
+    cap: Capability,
+    sender: Sender<Vec<u8>>,
+}
+
+impl Watcher {
+    fn permitted(&self, now: u64) -> bool {
+        self.cap.allows(Access::Subscribe, &self.room, now)
+    }
+}
+
+/**
+ * Transient per-client state (cursors, status, etc), grouped into rooms. A room is usually named
+ * after a document. Clients are named after their capability (see Capability::holder), so they
+ * can't change each other's state. Presence is never written to the op db, and entries go away
+ * when their TTL expires or when the client's watch connection drops. Watchers of a room get the
+ * room's current state, then set and remove events.
+ */
+#[derive(Debug, Default)]
+pub(crate) struct Presence {
+    rooms: BTreeMap<String, BTreeMap<String, Entry>>,
+    watchers: Vec<Watcher>,
+}
+
+impl Presence {
+    /** The state of every client in a room, as {client: state}. */
+    pub(crate) fn get(&self, room: &str) -> Value {
+        Value::Object(self.rooms.get(room).into_iter()
+            .flatten()
+            .map(|(client, entry)| (client.clone(), entry.state.clone()))
+            .collect::<Map<String, Value>>())
+    }
+
+    pub(crate) fn set(&mut self, room: &str, client: &str, state: Value, ttl: Duration, now: Instant) {
+        let event = serde_json::json!({ "event": "set", "client": client, "state": state });
+        self.rooms.entry(room.to_string()).or_default()
+            .insert(client.to_string(), Entry { state, expires: now + ttl.min(MAX_TTL) });
+        self.broadcast(room, &event);
+    }
+
+    /** Returns false if the client had no state in the room. */
+    pub(crate) fn remove(&mut self, room: &str, client: &str) -> bool {
+        let removed = match self.rooms.get_mut(room) {
+            Some(clients) => {
+                let removed = clients.remove(client).is_some();
+                if clients.is_empty() { self.rooms.remove(room); }
+                removed
+            },
+            None => false,
+        };
+        if removed {
+            self.broadcast(room, &serde_json::json!({ "event": "remove", "client": client }));
+        }
+        removed
+    }
+
+    /**
+     * Watch a room. The client's state in the room is removed when the watcher disconnects, or
+     * when its capability expires.
+     */
+    pub(crate) fn watch(&mut self, room: &str, cap: Capability, sender: Sender<Vec<u8>>) {
+        let snapshot = serde_json::json!({ "event": "snapshot", "clients": self.get(room) });
+        if sender.try_send(frame(None, &serde_json::to_vec(&snapshot).unwrap())).is_ok() {
+            self.watchers.push(Watcher { room: room.to_string(), client: cap.holder(), cap, sender });
+        }
+    }
+
+    /**
+     * Remove expired entries, and the entries of clients whose connections have dropped or whose
+     * capabilities have expired.
+     */
+    pub(crate) fn sweep(&mut self, now: Instant) {
+        let mut gone: Vec<(String, String)> = Vec::new();
+        for (room, clients) in self.rooms.iter() {
+            for (client, entry) in clients.iter() {
+                if entry.expires <= now { gone.push((room.clone(), client.clone())); }
+            }
+        }
+
+        // We only find out a client has gone when writing to its connection fails, so send every
+        // watcher a heartbeat (a blank line).
+        let unix_now = capability::now();
+        let (closed, open): (Vec<Watcher>, Vec<Watcher>) = self.watchers.drain(..)
+            .partition(|w| !w.permitted(unix_now) || w.sender.try_send(b"\r\n".to_vec()).is_err());
+        self.watchers = open;
+        for w in closed {
+            // The client might still be connected to the room another way.
+            let connected = self.watchers.iter().any(|o| o.room == w.room && o.client == w.client);
+            if !connected { gone.push((w.room, w.client)); }
+        }
+
+        for (room, client) in gone {
+            self.remove(&room, &client);
+        }
+    }
+
+    fn broadcast(&mut self, room: &str, event: &Value) {
+        let body = serde_json::to_vec(event).unwrap();
+        let now = capability::now();
+        // Watchers whose capabilities have expired are skipped here, and removed by sweep.
+        self.watchers.retain(|w| {
+            w.room != room || !w.permitted(now) || w.sender.try_send(frame(None, &body)).is_ok()
+        });
+    }
+}
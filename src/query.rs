@@ -0,0 +1,106 @@
+use crate::types::*;
+use crate::op_db::OpDb;
+use crate::view_db::ViewDb;
+use crate::links::{self, Link};
+use serde_json::{Value, Map};
+
+/** Queries can follow links (and keys) this many levels deep. This stops link cycles. */
+pub const MAX_QUERY_DEPTH: usize = 16;
+
+/**
+ * GraphQL-like queries. A query is a JSON object whose fields are document keys, and each field's
+ * value selects what to return from that document:
+ *
+ * - `true` returns the whole value
+ * - An object returns just the named fields, each with its own selection
+ *
+ * If a selection with fields is applied to a link (see links::Link) or a string, the selection is
+ * applied to the linked document instead. Strings are treated as document keys. Lists are
+ * selected item by item. So `{"users/1": {"name": true, "friends": {"name": true}}}` returns the user's name and
+ * the names of each of their friends.
+ *
+ * Missing documents are null, and documents with conflicting versions are returned as
+ * `{"$conflict": [...]}` with the selection applied to each version.
+ *
+ * Each document's key is passed to readable before the document is read, and the query fails if
+ * the caller can't read it. Queries which follow links more than MAX_QUERY_DEPTH levels deep fail.
+ */
+pub fn evaluate(ops: &OpDb, view: &ViewDb, query: &Value, readable: &dyn Fn(&DocId) -> bool) -> Result<Value, QueryError> {
+    let query = query.as_object().ok_or_else(|| invalid("Query must be an object".to_string()))?;
+
+    let mut result = Map::new();
+    for (key, selection) in query {
+        let link = Link { key: key.clone(), version: None };
+        result.insert(key.clone(), select_doc(ops, view, &link, selection, 0, readable)?);
+    }
+    Ok(Value::Object(result))
+}
+
+/** Why a query failed. */
+#[derive(Debug)]
+pub enum QueryError {
+    /** The query is malformed, or doesn't fit the documents it reads. */
+    Invalid(String),
+    /** The query reads a document the caller can't read. */
+    Unreadable(DocId),
+}
+
+fn invalid(message: String) -> QueryError {
+    QueryError::Invalid(message)
+}
+
+fn select_doc(ops: &OpDb, view: &ViewDb, link: &Link, selection: &Value, depth: usize, readable: &dyn Fn(&DocId) -> bool) -> Result<Value, QueryError> {
+    if depth > MAX_QUERY_DEPTH {
+        return Err(invalid(format!("Query follows links more than {} levels deep", MAX_QUERY_DEPTH)));
+    }
+    if !readable(&link.key) { return Err(QueryError::Unreadable(link.key.clone())); }
+
+    let vals = links::target_values(ops, view, link);
+    let mut results = Vec::with_capacity(vals.len());
+    for value in vals {
+        let json = match value {
+            DocValue::None => Value::Null,
+            value => value.json().ok_or_else(|| invalid(format!("{} is not a JSON document", link.key)))?,
+        };
+        results.push(select(ops, view, &json, selection, depth, readable)?);
+    }
+
+    Ok(if results.len() == 1 {
+        results.pop().unwrap()
+    } else {
+        serde_json::json!({ "$conflict": results })
+    })
+}
+
+fn select(ops: &OpDb, view: &ViewDb, value: &Value, selection: &Value, depth: usize, readable: &dyn Fn(&DocId) -> bool) -> Result<Value, QueryError> {
+    let fields = match selection {
+        Value::Bool(true) => return Ok(value.clone()),
+        Value::Object(fields) => fields,
+        _ => return Err(invalid(format!("Invalid selection {}", selection))),
+    };
+
+    if let Some(link) = Link::from_json(value) {
+        return select_doc(ops, view, &link, selection, depth + 1, readable);
+    }
+
+    match value {
+        Value::Null => Ok(Value::Null),
+        Value::String(key) => select_doc(ops, view, &Link { key: key.clone(), version: None }, selection, depth + 1, readable),
+        Value::Array(items) => items.iter()
+            .map(|item| select(ops, view, item, selection, depth, readable))
+            .collect::<Result<Vec<Value>, QueryError>>()
+            .map(Value::Array),
+        Value::Object(obj) => {
+            let mut result = Map::new();
+            for (field, sub) in fields {
+                let v = match obj.get(field) {
+                    Some(v) => select(ops, view, v, sub, depth, readable)?,
+                    None => Value::Null,
+                };
+                result.insert(field.clone(), v);
+            }
+            Ok(Value::Object(result))
+        },
+        _ => Err(invalid(format!("Cannot select fields from {}", value))),
+    }
+}
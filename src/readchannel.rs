@@ -19,8 +19,12 @@ pin_project_lite::pin_project! {
     }
 }
 
-pub(crate) fn channel() -> (channel::Sender<Vec<u8>>, IOReadChannel) {
-    let (sender, receiver) = channel::bounded(1);
+/// An unbounded channel, for writers which can't wait for the reader (eg subscriptions)
+pub(crate) fn unbounded_channel() -> (channel::Sender<Vec<u8>>, IOReadChannel) {
+    reader_for(channel::unbounded())
+}
+
+fn reader_for((sender, receiver): (channel::Sender<Vec<u8>>, channel::Receiver<Vec<u8>>)) -> (channel::Sender<Vec<u8>>, IOReadChannel) {
     let reader = IOReadChannel {
         receiver,
         buf: Box::default(),
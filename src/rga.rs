@@ -0,0 +1,135 @@
+use crate::types::*;
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+
+/**
+ * Every item inserted into a CRDT has a globally unique ID made from the version of the operation
+ * which inserted it, and its offset within that operation's patch.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ItemId {
+    pub agent: String,
+    pub seq: Seq,
+    pub offset: u32,
+}
+
+impl ItemId {
+    pub fn new(version: &RemoteVersion, offset: u32) -> Self {
+        ItemId { agent: version.agent.clone(), seq: version.seq, offset }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct RgaItem<T> {
+    /** The item this was inserted directly after. None for the start of the sequence. */
+    origin: Option<ItemId>,
+    /** One more than the largest lamport timestamp in the sequence when this was inserted. */
+    lamport: u64,
+    value: T,
+    deleted: bool,
+}
+
+/**
+ * A replicated sequence (RGA). The sequence is a tree of items, where each item is a child of the
+ * item it was inserted after. Siblings are sorted newest first by (lamport, id), so the order is a
+ * deterministic function of the set of items. That means any two states can be merged by taking
+ * the union of their items, which is how concurrent edits converge.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rga<T> {
+    items: BTreeMap<ItemId, RgaItem<T>>,
+}
+
+impl<T> Default for Rga<T> {
+    fn default() -> Self {
+        Rga { items: BTreeMap::new() }
+    }
+}
+
+impl<T: Clone> Rga<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_lamport(&self) -> u64 {
+        self.items.values().map(|item| item.lamport).max().unwrap_or(0)
+    }
+
+    /** All item IDs (including deleted items) in sequence order. */
+    fn ordered_ids(&self) -> Vec<&ItemId> {
+        let mut children: BTreeMap<Option<&ItemId>, Vec<&ItemId>> = BTreeMap::new();
+        for (id, item) in self.items.iter() {
+            children.entry(item.origin.as_ref()).or_default().push(id);
+        }
+        for siblings in children.values_mut() {
+            siblings.sort_by(|a, b| {
+                let (a_item, b_item) = (&self.items[*a], &self.items[*b]);
+                (b_item.lamport, b).cmp(&(a_item.lamport, a))
+            });
+        }
+
+        let mut result = Vec::with_capacity(self.items.len());
+        // Depth first traversal. The stack holds children in reverse so we pop the first one.
+        let mut stack: Vec<&ItemId> = children.get(&None)
+            .map(|c| c.iter().rev().copied().collect())
+            .unwrap_or_default();
+        while let Some(id) = stack.pop() {
+            result.push(id);
+            if let Some(c) = children.get(&Some(id)) {
+                stack.extend(c.iter().rev());
+            }
+        }
+        result
+    }
+
+    /** IDs of the items which haven't been deleted, in sequence order. */
+    pub fn visible_ids(&self) -> Vec<&ItemId> {
+        self.ordered_ids().into_iter()
+            .filter(|id| !self.items[*id].deleted)
+            .collect()
+    }
+
+    /** Values of the items which haven't been deleted, in sequence order. */
+    pub fn visible(&self) -> impl Iterator<Item=&T> {
+        self.visible_ids().into_iter().map(move |id| &self.items[id].value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.values().filter(|item| !item.deleted).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /** Merge another replica's state into this one. */
+    pub fn merge(&mut self, other: &Rga<T>) {
+        for (id, item) in other.items.iter() {
+            match self.items.get_mut(id) {
+                Some(existing) => existing.deleted |= item.deleted,
+                None => { self.items.insert(id.clone(), item.clone()); }
+            }
+        }
+    }
+
+    pub fn insert(&mut self, id: ItemId, origin: Option<ItemId>, lamport: u64, value: T) -> Result<(), String> {
+        if let Some(o) = &origin {
+            if !self.items.contains_key(o) {
+                return Err(format!("Unknown insert position {}/{}/{}", o.agent, o.seq, o.offset));
+            }
+        }
+        if self.items.contains_key(&id) {
+            return Err(format!("Duplicate item {}/{}/{}", id.agent, id.seq, id.offset));
+        }
+
+        self.items.insert(id, RgaItem { origin, lamport, value, deleted: false });
+        Ok(())
+    }
+
+    pub fn delete(&mut self, id: &ItemId) -> Result<(), String> {
+        let item = self.items.get_mut(id)
+            .ok_or_else(|| format!("Cannot delete unknown item {}/{}/{}", id.agent, id.seq, id.offset))?;
+        item.deleted = true;
+        Ok(())
+    }
+}
@@ -0,0 +1,263 @@
+use crate::types::*;
+use serde_json::Value;
+use std::fmt;
+
+/**
+ * Schemas are registered against key glob patterns (see glob_matches) and checked on every write.
+ * Schemas are a subset of JSON schema:
+ *
+ * - `type`: A type name ("null", "boolean", "number", "integer", "string", "array", "object") or
+ *   a list of type names
+ * - `enum`: List of allowed values
+ * - `properties`, `required` and `additionalProperties` for objects
+ * - `items` for arrays
+ *
+ * Schemas can evolve. Registering a schema for a pattern which already has one adds a new
+ * version, and subsequent writes are checked against the newest version. Existing documents are
+ * left alone.
+ */
+#[derive(Debug, Default)]
+pub struct SchemaRegistry {
+    entries: Vec<SchemaEntry>,
+}
+
+#[derive(Debug)]
+struct SchemaEntry {
+    pattern: String,
+    /** All versions of the schema, oldest first. */
+    versions: Vec<Value>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationError {
+    pub key: DocId,
+    /** JSON pointer to the invalid part of the document */
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid value for {} at '{}': {}", self.key, self.path, self.message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+const TYPE_NAMES: [&str; 7] = ["null", "boolean", "number", "integer", "string", "array", "object"];
+
+/**
+ * Check if a key matches a pattern. Patterns are split into segments by '/'. A `*` segment
+ * matches any single segment, and a trailing `**` matches any number of segments.
+ */
+pub(crate) fn glob_matches(pattern: &str, key: &str) -> bool {
+    let mut key_segments = key.split('/');
+    for p in pattern.split('/') {
+        if p == "**" { return true; }
+
+        match key_segments.next() {
+            None => return false,
+            Some(k) => if p != "*" && p != k { return false; }
+        }
+    }
+
+    key_segments.next().is_none()
+}
+
+fn type_matches(type_name: &str, value: &Value) -> bool {
+    match type_name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => false
+    }
+}
+
+/** Check the schema itself is well formed, so we don't find out at write time. */
+fn check_schema(schema: &Value, path: &str) -> Result<(), String> {
+    let obj = schema.as_object()
+        .ok_or_else(|| format!("Schema at '{}' must be an object", path))?;
+
+    if let Some(t) = obj.get("type") {
+        let names: Vec<&Value> = match t {
+            Value::Array(names) => names.iter().collect(),
+            t => vec!(t),
+        };
+        for name in names {
+            if !name.as_str().is_some_and(|n| TYPE_NAMES.contains(&n)) {
+                return Err(format!("Unknown type {} in schema at '{}'", name, path));
+            }
+        }
+    }
+
+    if let Some(e) = obj.get("enum") {
+        if !e.is_array() { return Err(format!("enum must be a list at '{}'", path)); }
+    }
+
+    if let Some(props) = obj.get("properties") {
+        let props = props.as_object()
+            .ok_or_else(|| format!("properties must be an object at '{}'", path))?;
+        for (name, prop) in props {
+            check_schema(prop, &format!("{}/properties/{}", path, name))?;
+        }
+    }
+
+    if let Some(required) = obj.get("required") {
+        if !required.as_array().is_some_and(|r| r.iter().all(|f| f.is_string())) {
+            return Err(format!("required must be a list of field names at '{}'", path));
+        }
+    }
+
+    match obj.get("additionalProperties") {
+        None | Some(Value::Bool(_)) => {},
+        Some(additional) => check_schema(additional, &format!("{}/additionalProperties", path))?,
+    }
+
+    if let Some(items) = obj.get("items") {
+        check_schema(items, &format!("{}/items", path))?;
+    }
+
+    Ok(())
+}
+
+fn validate(schema: &Value, value: &Value, path: &mut String) -> Result<(), (String, String)> {
+    let fail = |path: &String, message: String| Err((path.clone(), message));
+
+    match schema.get("type") {
+        Some(Value::String(t)) if !type_matches(t, value) => {
+            return fail(path, format!("Expected {}", t));
+        },
+        Some(Value::Array(types)) if !types.iter().any(|t| type_matches(t.as_str().unwrap(), value)) => {
+            return fail(path, format!("Expected one of {}", Value::Array(types.clone())));
+        },
+        _ => {}
+    }
+
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            return fail(path, format!("Expected one of {}", Value::Array(allowed.clone())));
+        }
+    }
+
+    if let Value::Object(obj) = value {
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for field in required {
+                let field = field.as_str().unwrap();
+                if !obj.contains_key(field) {
+                    return fail(path, format!("Missing required field '{}'", field));
+                }
+            }
+        }
+
+        let props = schema.get("properties").and_then(|p| p.as_object());
+        for (field, child) in obj {
+            let child_schema = match props.and_then(|p| p.get(field)) {
+                Some(s) => s,
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        return fail(path, format!("Unexpected field '{}'", field));
+                    },
+                    Some(s @ Value::Object(_)) => s,
+                    _ => continue,
+                }
+            };
+
+            let len = path.len();
+            path.push('/');
+            path.push_str(field);
+            validate(child_schema, child, path)?;
+            path.truncate(len);
+        }
+    }
+
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (i, item) in items.iter().enumerate() {
+            let len = path.len();
+            path.push_str(&format!("/{}", i));
+            validate(item_schema, item, path)?;
+            path.truncate(len);
+        }
+    }
+
+    Ok(())
+}
+
+impl SchemaRegistry {
+    /**
+     * Register a schema for the specified key pattern. If the pattern already has a schema this
+     * adds a new version. Returns the version number of the schema.
+     */
+    pub fn register(&mut self, pattern: &str, schema: Value) -> Result<usize, String> {
+        check_schema(&schema, "")?;
+
+        match self.entries.iter_mut().find(|e| e.pattern == pattern) {
+            Some(entry) => {
+                entry.versions.push(schema);
+                Ok(entry.versions.len() - 1)
+            },
+            None => {
+                self.entries.push(SchemaEntry {
+                    pattern: pattern.to_string(),
+                    versions: vec!(schema)
+                });
+                Ok(0)
+            }
+        }
+    }
+
+    /** Get the current version of the schema registered for a pattern. */
+    pub fn get(&self, pattern: &str) -> Option<(usize, &Value)> {
+        self.entries.iter()
+            .find(|e| e.pattern == pattern)
+            .map(|e| (e.versions.len() - 1, e.versions.last().unwrap()))
+    }
+
+    /** Iterate through (pattern, current version, current schema) for all registered schemas. */
+    pub fn iter(&self) -> impl Iterator<Item=(&str, usize, &Value)> {
+        self.entries.iter()
+            .map(|e| (e.pattern.as_str(), e.versions.len() - 1, e.versions.last().unwrap()))
+    }
+
+    pub fn has_schema(&self, key: &DocId) -> bool {
+        self.entries.iter().any(|e| glob_matches(&e.pattern, key))
+    }
+
+    /**
+     * Check a JSON value against the current version of every schema whose pattern matches the
+     * key.
+     */
+    pub fn validate_json(&self, key: &DocId, value: &Value) -> Result<(), ValidationError> {
+        for entry in self.entries.iter().filter(|e| glob_matches(&e.pattern, key)) {
+            let mut path = String::new();
+            validate(entry.versions.last().unwrap(), value, &mut path)
+                .map_err(|(path, message)| ValidationError {
+                    key: key.clone(),
+                    path,
+                    message
+                })?;
+        }
+        Ok(())
+    }
+
+    /** Check a document value about to be written to the specified key. */
+    pub fn validate(&self, key: &DocId, value: &DocValue) -> Result<(), ValidationError> {
+        match value {
+            // Deletes are always allowed.
+            DocValue::None => Ok(()),
+            DocValue::Blob { .. } => {
+                if self.has_schema(key) {
+                    Err(ValidationError {
+                        key: key.clone(),
+                        path: String::new(),
+                        message: "Expected a JSON document".to_string()
+                    })
+                } else { Ok(()) }
+            },
+            value => self.validate_json(key, &value.json().unwrap()),
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::types::*;
+use crate::rga::ItemId;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use serde::{Serialize, Deserialize};
+
+/**
+ * Set operations. Removes name the add tags which were observed when the remove was generated
+ * (see remove_op), so an add concurrent with a remove survives.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SetOp {
+    Add(Value),
+    Remove(Vec<ItemId>),
+}
+
+/**
+ * An observed-remove set of JSON values. Every add is tagged with a unique ID, and an element is in
+ * the set while any of its tags haven't been removed. Replicas merge by taking the union of their
+ * adds and removes.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrSet {
+    adds: BTreeMap<ItemId, Value>,
+    removed: BTreeSet<ItemId>,
+}
+
+impl OrSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** Live tags, in tag order. */
+    fn live(&self) -> impl Iterator<Item=(&ItemId, &Value)> {
+        self.adds.iter().filter(move |(tag, _)| !self.removed.contains(tag))
+    }
+
+    /**
+     * The elements of the set, in the order of their first live tag. Tags sort by agent, then
+     * seq, so this isn't the order elements were added in, but it is the same on every replica.
+     */
+    pub fn values(&self) -> Vec<&Value> {
+        // Values aren't hashable, but their encodings are canonical (objects are sorted by key).
+        let mut seen = BTreeSet::new();
+        self.live()
+            .filter(|(_, value)| seen.insert(value.to_string()))
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    /** The set as a JSON list. */
+    pub fn to_json(&self) -> Value {
+        Value::Array(self.values().into_iter().cloned().collect())
+    }
+
+    pub fn contains(&self, value: &Value) -> bool {
+        self.live().any(|(_, v)| v == value)
+    }
+
+    /** Merge another replica's state into this one. */
+    pub fn merge(&mut self, other: &OrSet) {
+        for (tag, value) in other.adds.iter() {
+            self.adds.entry(tag.clone()).or_insert_with(|| value.clone());
+        }
+        self.removed.extend(other.removed.iter().cloned());
+    }
+
+    /** Generate an operation removing a value from the set. Returns None if it isn't in the set. */
+    pub fn remove_op(&self, value: &Value) -> Option<SetOp> {
+        let tags: Vec<ItemId> = self.live()
+            .filter(|(_, v)| *v == value)
+            .map(|(tag, _)| tag.clone())
+            .collect();
+        if tags.is_empty() { None } else { Some(SetOp::Remove(tags)) }
+    }
+
+    /** Generate operations to change this set's elements to target. */
+    pub fn diff_ops(&self, target: &[Value]) -> Vec<SetOp> {
+        let mut ops: Vec<SetOp> = self.values().into_iter()
+            .filter(|v| !target.contains(v))
+            .filter_map(|v| self.remove_op(v))
+            .collect();
+
+        for value in target {
+            if !self.contains(value) && !ops.contains(&SetOp::Add(value.clone())) {
+                ops.push(SetOp::Add(value.clone()));
+            }
+        }
+        ops
+    }
+
+    /** Apply operations from the patch written by the operation with the specified version. */
+    pub fn apply(&mut self, ops: &[SetOp], version: &RemoteVersion) -> Result<(), String> {
+        for (offset, op) in ops.iter().enumerate() {
+            match op {
+                SetOp::Add(value) => {
+                    self.adds.insert(ItemId::new(version, offset as u32), value.clone());
+                },
+                SetOp::Remove(tags) => {
+                    for tag in tags {
+                        if !self.adds.contains_key(tag) {
+                            return Err(format!("Cannot remove unknown tag {}/{}/{}", tag.agent, tag.seq, tag.offset));
+                        }
+                        self.removed.insert(tag.clone());
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn version(agent: &str, seq: Seq) -> RemoteVersion {
+        RemoteVersion { agent: agent.to_string(), seq }
+    }
+
+    fn edit(set: &OrSet, target: &[Value], version: &RemoteVersion) -> OrSet {
+        let ops = set.diff_ops(target);
+        let mut set = set.clone();
+        set.apply(&ops, version).unwrap();
+        set
+    }
+
+    /** Merge two replicas in both orders, check they agree, and return the merged value. */
+    fn converge(a: &OrSet, b: &OrSet) -> Value {
+        let mut ab = a.clone();
+        ab.merge(b);
+        let mut ba = b.clone();
+        ba.merge(a);
+        assert_eq!(ab, ba);
+        ab.to_json()
+    }
+
+    #[test]
+    fn concurrent_edits_converge() {
+        let base = edit(&OrSet::new(), &[json!(1), json!("x")], &version("a", 0));
+        let b = edit(&base, &[json!(1), json!("x"), json!({"k": 2})], &version("b", 0));
+        let c = edit(&base, &[json!("x"), json!(3)], &version("c", 0));
+        assert_eq!(converge(&b, &c), json!(["x", {"k": 2}, 3]));
+    }
+
+    #[test]
+    fn concurrent_add_wins() {
+        let base = edit(&OrSet::new(), &[json!(1)], &version("a", 0));
+        let b = edit(&base, &[], &version("b", 0));
+        let mut c = base.clone();
+        c.apply(&[SetOp::Add(json!(1))], &version("c", 0)).unwrap();
+        // c re-adds the value b removes. b hadn't seen that add, so it survives.
+        assert_eq!(converge(&b, &c), json!([1]));
+
+        // Removing it after merging removes every tag.
+        let mut merged = b.clone();
+        merged.merge(&c);
+        let op = merged.remove_op(&json!(1)).unwrap();
+        merged.apply(&[op], &version("b", 1)).unwrap();
+        assert_eq!(converge(&merged, &c), json!([]));
+    }
+
+    #[test]
+    fn concurrent_removes_converge() {
+        let base = edit(&OrSet::new(), &[json!(1), json!(2)], &version("a", 0));
+        let b = edit(&base, &[json!(2)], &version("b", 0));
+        let c = edit(&base, &[json!(2)], &version("c", 0));
+        assert_eq!(converge(&b, &c), json!([2]));
+    }
+
+    #[test]
+    fn unknown_tags_rejected() {
+        let op = SetOp::Remove(vec![ItemId::new(&version("a", 0), 0)]);
+        assert!(OrSet::new().apply(&[op], &version("b", 0)).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use crate::types::*;
+use crate::error::DbError;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::convert::TryInto;
+
+/**
+ * The bytes an operation's signature covers. This is the MessagePack encoding of everything in
+ * the operation except the signature itself. Maps are encoded in key order, so the encoding is
+ * canonical.
+ */
+pub(crate) fn signed_bytes(op: &RemoteOperation) -> Vec<u8> {
+    rmp_serde::to_vec(&(&op.version, &op.succeeds, &op.parents, &op.doc_ops))
+        .expect("Operation could not be encoded")
+}
+
+/** The key this node signs its own operations with. */
+pub struct LocalKey {
+    key: SigningKey,
+}
+
+impl fmt::Debug for LocalKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Only show the public half.
+        write!(f, "LocalKey({})", base64::encode(self.public_key().as_bytes()))
+    }
+}
+
+impl Default for LocalKey {
+    fn default() -> Self {
+        let mut seed = [0; 32];
+        getrandom::getrandom(&mut seed).expect("Could not generate a signing key");
+        LocalKey::from_seed(seed)
+    }
+}
+
+impl LocalKey {
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        LocalKey { key: SigningKey::from_bytes(&seed) }
+    }
+
+    pub fn from_base64(seed: &str) -> Option<Self> {
+        let seed: [u8; 32] = base64::decode(seed.trim()).ok()?.try_into().ok()?;
+        Some(LocalKey::from_seed(seed))
+    }
+
+    pub fn public_key(&self) -> VerifyingKey {
+        self.key.verifying_key()
+    }
+
+    pub fn sign(&self, op: &mut RemoteOperation) {
+        op.signature = self.key.sign(&signed_bytes(op)).to_bytes().to_vec();
+    }
+}
+
+/**
+ * The public key each agent signs its operations with. Agents are bound to a key once, and
+ * operations from an agent are only accepted if they're signed with its key. Without this any
+ * peer could write operations as any agent.
+ */
+#[derive(Debug, Default)]
+pub struct Keyring {
+    keys: BTreeMap<String, VerifyingKey>,
+}
+
+/** Parse a base64 encoded ed25519 public key. */
+pub fn parse_public_key(encoded: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = base64::decode(encoded.trim()).ok()?.try_into().ok()?;
+    public_key_from_bytes(&bytes)
+}
+
+/**
+ * The agent name a node with this public key writes operations as. It's made from the key, so
+ * every node has its own agent and nodes can't write conflicting operations under the same name.
+ */
+pub fn agent_name(key: &VerifyingKey) -> String {
+    base64::encode_config(&key.as_bytes()[..16], base64::URL_SAFE_NO_PAD)
+}
+
+pub fn public_key_from_bytes(bytes: &[u8; 32]) -> Option<VerifyingKey> {
+    VerifyingKey::from_bytes(bytes).ok()
+}
+
+impl Keyring {
+    /** Check an agent could be bound to a key. Agents can't be rebound to a different key. */
+    pub fn check(&self, agent: &str, key: &VerifyingKey) -> Result<(), String> {
+        match self.keys.get(agent) {
+            Some(existing) if existing != key => Err(format!("Agent {} is bound to a different key", agent)),
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * Bind an agent to a public key. Agents can't be bound to a different key later, because
+     * that would let whoever holds the new key rewrite the agent's history.
+     */
+    pub fn bind(&mut self, agent: &str, key: VerifyingKey) -> Result<(), String> {
+        self.check(agent, &key)?;
+        self.keys.insert(agent.to_string(), key);
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&String, &VerifyingKey)> {
+        self.keys.iter()
+    }
+
+    /** Check an operation is signed by its agent. */
+    pub fn verify(&self, op: &RemoteOperation) -> Result<(), DbError> {
+        let key = self.keys.get(&op.version.agent)
+            .ok_or_else(|| DbError::UnknownAgent(op.version.agent.clone()))?;
+        let signature = Signature::from_slice(&op.signature)
+            .map_err(|_| DbError::BadSignature(op.version.clone()))?;
+
+        key.verify_strict(&signed_bytes(op), &signature)
+            .map_err(|_| DbError::BadSignature(op.version.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::version::ROOT_AGENT_STR;
+
+    fn signed_op(key: &LocalKey) -> RemoteOperation {
+        let root = RemoteVersion { agent: ROOT_AGENT_STR.to_string(), seq: 0 };
+        let mut op = RemoteOperation {
+            version: RemoteVersion { agent: agent_name(&key.public_key()), seq: 0 },
+            succeeds: None,
+            parents: vec!(root.clone()),
+            doc_ops: vec!(RemoteDocOp {
+                id: "a".to_string(),
+                patch: DocPatch::Replace(DocValue::Json(serde_json::json!(1))),
+                parents: vec!(root),
+            }),
+            hash: [0; 32],
+            signature: vec!(),
+        };
+        key.sign(&mut op);
+        op
+    }
+
+    fn keyring(key: &LocalKey) -> Keyring {
+        let mut keys = Keyring::default();
+        keys.bind(&agent_name(&key.public_key()), key.public_key()).unwrap();
+        keys
+    }
+
+    #[test]
+    fn signed_ops_verify() {
+        let key = LocalKey::from_seed([1; 32]);
+        keyring(&key).verify(&signed_op(&key)).unwrap();
+    }
+
+    #[test]
+    fn unknown_agent() {
+        let key = LocalKey::from_seed([1; 32]);
+        let result = Keyring::default().verify(&signed_op(&key));
+        assert!(matches!(result, Err(DbError::UnknownAgent(_))));
+    }
+
+    #[test]
+    fn bad_signatures() {
+        let key = LocalKey::from_seed([1; 32]);
+        let keys = keyring(&key);
+
+        let mut changed = signed_op(&key);
+        changed.doc_ops[0].patch = DocPatch::Replace(DocValue::Json(serde_json::json!(2)));
+        assert!(matches!(keys.verify(&changed), Err(DbError::BadSignature(_))));
+
+        // Signed by someone else, claiming to be our agent.
+        let mut forged = signed_op(&key);
+        LocalKey::from_seed([2; 32]).sign(&mut forged);
+        assert!(matches!(keys.verify(&forged), Err(DbError::BadSignature(_))));
+
+        let mut truncated = signed_op(&key);
+        truncated.signature.pop();
+        assert!(matches!(keys.verify(&truncated), Err(DbError::BadSignature(_))));
+    }
+
+    #[test]
+    fn agents_cannot_be_rebound() {
+        let key = LocalKey::from_seed([1; 32]);
+        let mut keys = keyring(&key);
+        let agent = agent_name(&key.public_key());
+
+        keys.bind(&agent, key.public_key()).unwrap();
+        assert!(keys.bind(&agent, LocalKey::from_seed([2; 32]).public_key()).is_err());
+    }
+}
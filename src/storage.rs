@@ -0,0 +1,501 @@
+use crate::types::*;
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, KeyInit};
+use chacha20poly1305::aead::{Aead, Payload};
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::convert::TryFrom;
+
+const MAGIC: &[u8; 8] = b"BRAIDDB2";
+const PLAIN: u8 = 0;
+const ENCRYPTED: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8;
+const NONCE_LEN: usize = 24;
+/** The index the terminator of a checkpoint is sealed with. */
+const TERMINATOR: u64 = u64::MAX;
+
+const LOG_FILE: &str = "ops.log";
+const LOG_END_FILE: &str = "ops.log.end";
+const CHECKPOINT_FILE: &str = "checkpoint";
+const KEY_FILE: &str = "signing.key";
+
+/** Write a checkpoint (and start a new log) after this many log records. */
+pub const CHECKPOINT_INTERVAL: u64 = 1000;
+
+/** Something which changed the op store. Replaying these rebuilds the database. */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Record {
+    Op(RemoteOperation),
+    /** An operation whose history has been pruned. These are only written in checkpoints. */
+    Pruned(PrunedOperation),
+    /** An agent was bound to a public key (see signing::Keyring). */
+    BindAgent { agent: String, key: [u8; 32] },
+    /** An ownership rule was added (see ownership::OwnershipRules). */
+    OwnershipRule(String),
+}
+
+/** Parse a base64 encoded 32 byte key. */
+pub fn parse_key(encoded: &str) -> Option<[u8; 32]> {
+    <[u8; 32]>::try_from(base64::decode(encoded.trim()).ok()?).ok()
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/**
+ * Optional encryption at rest. Each record is encrypted separately with XChaCha20-Poly1305 and a
+ * random nonce. The associated data names the file, its generation and the record's position in
+ * it, so records which are modified, reordered or moved between files (or between generations of
+ * a file) fail to decrypt.
+ */
+struct Sealer {
+    cipher: Option<XChaCha20Poly1305>,
+}
+
+impl Sealer {
+    fn mode(&self) -> u8 {
+        if self.cipher.is_some() { ENCRYPTED } else { PLAIN }
+    }
+
+    fn aad(file: &str, generation: u64, index: u64) -> Vec<u8> {
+        let mut aad = file.as_bytes().to_vec();
+        aad.extend_from_slice(&generation.to_be_bytes());
+        aad.extend_from_slice(&index.to_be_bytes());
+        aad
+    }
+
+    fn seal(&self, file: &str, generation: u64, index: u64, data: Vec<u8>) -> Vec<u8> {
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => return data,
+        };
+
+        let mut nonce = [0; NONCE_LEN];
+        getrandom::getrandom(&mut nonce).expect("Could not generate a nonce");
+        let aad = Self::aad(file, generation, index);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(cipher.encrypt(XNonce::from_slice(&nonce), Payload { msg: &data, aad: &aad })
+            .expect("Encryption failed"));
+        sealed
+    }
+
+    fn open(&self, file: &str, generation: u64, index: u64, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => return Ok(data),
+        };
+
+        if data.len() < NONCE_LEN { return Err(invalid(format!("{} record {} is truncated", file, index))); }
+        let (nonce, msg) = data.split_at(NONCE_LEN);
+        let aad = Self::aad(file, generation, index);
+        cipher.decrypt(XNonce::from_slice(nonce), Payload { msg, aad: &aad })
+            .map_err(|_| invalid(format!("{} record {} has been tampered with (or the key is wrong)", file, index)))
+    }
+}
+
+/** A file of records, before the records are opened. */
+struct RawFile {
+    name: String,
+    generation: u64,
+    frames: Vec<Vec<u8>>,
+    /** The length of the file up to the end of the last whole frame. */
+    len: u64,
+    /** The file ends with part of a frame, from a write which didn't finish. */
+    torn: bool,
+}
+
+impl RawFile {
+    fn open_records(self, sealer: &Sealer) -> io::Result<Vec<Vec<u8>>> {
+        let (name, generation) = (self.name, self.generation);
+        self.frames.into_iter().enumerate()
+            .map(|(i, frame)| sealer.open(&name, generation, i as u64, frame))
+            .collect()
+    }
+}
+
+/**
+ * Read a file of records. Files start with a header naming whether they're encrypted and the
+ * file's generation, followed by length prefixed records.
+ */
+fn read_file(path: &Path, sealer: &Sealer) -> io::Result<RawFile> {
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+    let mut data = vec!();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+        return Err(invalid(format!("{} is not a database file", name)));
+    }
+    match (data[MAGIC.len()], sealer.mode()) {
+        (a, b) if a == b => {},
+        (ENCRYPTED, _) => return Err(invalid(format!("{} is encrypted, but no key was given", name))),
+        _ => return Err(invalid(format!("{} is not encrypted", name))),
+    }
+    let mut generation = [0; 8];
+    generation.copy_from_slice(&data[MAGIC.len() + 1..HEADER_LEN]);
+
+    let mut frames = vec!();
+    let mut pos = HEADER_LEN;
+    let mut torn = false;
+    while pos < data.len() {
+        let rest = &data[pos..];
+        let len = if rest.len() < 4 { None } else {
+            Some(u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize)
+        };
+        match len {
+            Some(len) if rest.len() >= 4 + len => {
+                frames.push(rest[4..4 + len].to_vec());
+                pos += 4 + len;
+            },
+            _ => {
+                torn = true;
+                break;
+            }
+        }
+    }
+    Ok(RawFile { name, generation: u64::from_be_bytes(generation), frames, len: pos as u64, torn })
+}
+
+fn frame(data: &[u8]) -> Vec<u8> {
+    let mut frame = (data.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(data);
+    frame
+}
+
+/**
+ * Write a file of records. The file is replaced atomically. Checkpoints end with a terminator
+ * holding the number of records, so records dropped from the end are noticed.
+ */
+fn write_file(path: &Path, sealer: &Sealer, generation: u64, records: Vec<Vec<u8>>, terminate: bool) -> io::Result<()> {
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+    let mut data = MAGIC.to_vec();
+    data.push(sealer.mode());
+    data.extend_from_slice(&generation.to_be_bytes());
+
+    let count = records.len() as u64;
+    for (i, record) in records.into_iter().enumerate() {
+        data.extend(frame(&sealer.seal(&name, generation, i as u64, record)));
+    }
+    if terminate {
+        data.extend(frame(&sealer.seal(&name, generation, TERMINATOR, count.to_be_bytes().to_vec())));
+    }
+
+    let tmp = path.with_file_name(format!("{}.tmp", name));
+    let mut file = File::create(&tmp)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+fn read_count(bytes: &[u8]) -> Option<u64> {
+    <[u8; 8]>::try_from(bytes).ok().map(u64::from_be_bytes)
+}
+
+/** Read a checkpoint, checking its terminator. Returns its generation and records. */
+fn read_checkpoint(path: &Path, sealer: &Sealer) -> io::Result<(u64, Vec<Vec<u8>>)> {
+    let mut raw = read_file(path, sealer)?;
+    let terminator = match raw.frames.pop() {
+        Some(frame) if !raw.torn => sealer.open(&raw.name, raw.generation, TERMINATOR, frame)?,
+        _ => return Err(invalid(format!("{} is truncated", CHECKPOINT_FILE))),
+    };
+    if read_count(&terminator) != Some(raw.frames.len() as u64) {
+        return Err(invalid(format!("{} is truncated", CHECKPOINT_FILE)));
+    }
+    let generation = raw.generation;
+    Ok((generation, raw.open_records(sealer)?))
+}
+
+/**
+ * Persistent storage for the op store, in a directory. Changes are appended to a log, and every so
+ * often the whole store is written to a checkpoint and the log is started again. Blobs are stored
+ * in the operations which write them.
+ *
+ * Each checkpoint starts a new generation, and the log is tied to the checkpoint's generation. The
+ * length of the log is also recorded (in ops.log.end) after every append, so records which go
+ * missing from the end of the log are noticed. A write which was cut off by a crash is discarded.
+ *
+ * If a key is given, everything (including our signing key) is encrypted at rest, and files
+ * which have been tampered with are detected when they're loaded. An older set of files (as a
+ * whole) still looks valid, because that can't be detected without keeping state elsewhere.
+ */
+pub struct Storage {
+    dir: PathBuf,
+    sealer: Sealer,
+    generation: u64,
+    /** None if a checkpoint failed part way through. No more records can be written until one succeeds. */
+    log: Option<File>,
+    log_records: u64,
+}
+
+impl fmt::Debug for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Storage({:?}, encrypted: {})", self.dir, self.sealer.cipher.is_some())
+    }
+}
+
+impl Storage {
+    /** Open (or create) storage in a directory. Returns the stored records, oldest first. */
+    pub fn open(dir: &Path, key: Option<[u8; 32]>) -> io::Result<(Storage, Vec<Record>)> {
+        fs::create_dir_all(dir)?;
+        let sealer = Sealer { cipher: key.map(|key| XChaCha20Poly1305::new(&key.into())) };
+
+        let checkpoint_path = dir.join(CHECKPOINT_FILE);
+        let log_path = dir.join(LOG_FILE);
+        let (generation, mut stored) = if checkpoint_path.exists() {
+            read_checkpoint(&checkpoint_path, &sealer)?
+        } else { (0, vec!()) };
+
+        let mut storage = Storage { dir: dir.to_path_buf(), sealer, generation, log: None, log_records: 0 };
+        if !log_path.exists() {
+            if checkpoint_path.exists() { return Err(invalid(format!("{} is missing", LOG_FILE))); }
+            storage.start_log()?;
+        } else {
+            let raw = read_file(&log_path, &storage.sealer)?;
+            if raw.generation + 1 == generation {
+                // We crashed while writing a checkpoint. It has everything in the old log.
+                storage.start_log()?;
+            } else if raw.generation != generation {
+                return Err(invalid(format!("{} doesn't belong to {}", LOG_FILE, CHECKPOINT_FILE)));
+            } else {
+                if raw.torn {
+                    let file = OpenOptions::new().write(true).open(&log_path)?;
+                    file.set_len(raw.len)?;
+                    file.sync_all()?;
+                }
+                let records = raw.open_records(&storage.sealer)?;
+                storage.check_log_end(records.len() as u64)?;
+                storage.log_records = records.len() as u64;
+                stored.extend(records);
+                storage.log = Some(OpenOptions::new().append(true).open(&log_path)?);
+            }
+        }
+
+        let records = stored.into_iter()
+            .map(|bytes| rmp_serde::from_slice::<Record>(&bytes)
+                .map_err(|e| invalid(format!("Invalid record: {}", e))))
+            .collect::<io::Result<Vec<Record>>>()?;
+        Ok((storage, records))
+    }
+
+    /** Check the log has at least as many records as it had the last time we wrote to it. */
+    fn check_log_end(&self, records: u64) -> io::Result<()> {
+        let path = self.dir.join(LOG_END_FILE);
+        if !path.exists() { return Ok(()); }
+
+        let raw = read_file(&path, &self.sealer)?;
+        let generation = raw.generation;
+        let count = raw.open_records(&self.sealer)?.first().and_then(|c| read_count(c))
+            .ok_or_else(|| invalid(format!("Invalid {}", LOG_END_FILE)))?;
+        if generation > self.generation || (generation == self.generation && count > records) {
+            return Err(invalid(format!("Records are missing from the end of {}", LOG_FILE)));
+        }
+        Ok(())
+    }
+
+    fn write_log_end(&self) -> io::Result<()> {
+        write_file(&self.dir.join(LOG_END_FILE), &self.sealer, self.generation,
+            vec!(self.log_records.to_be_bytes().to_vec()), false)
+    }
+
+    /** Start an empty log for the current generation. */
+    fn start_log(&mut self) -> io::Result<()> {
+        let log_path = self.dir.join(LOG_FILE);
+        write_file(&log_path, &self.sealer, self.generation, vec!(), false)?;
+        self.log_records = 0;
+        self.write_log_end()?;
+        self.log = Some(OpenOptions::new().append(true).open(&log_path)?);
+        Ok(())
+    }
+
+    /** Our signing key's seed, which is made the first time storage is used. */
+    pub fn signing_seed(&self) -> io::Result<[u8; 32]> {
+        let path = self.dir.join(KEY_FILE);
+        if path.exists() {
+            let records = read_file(&path, &self.sealer)?.open_records(&self.sealer)?;
+            let seed = records.first().and_then(|seed| <[u8; 32]>::try_from(&seed[..]).ok());
+            return seed.ok_or_else(|| invalid(format!("Invalid {}", KEY_FILE)));
+        }
+
+        let mut seed = [0; 32];
+        getrandom::getrandom(&mut seed).expect("Could not generate a signing key");
+        write_file(&path, &self.sealer, 0, vec!(seed.to_vec()), false)?;
+        Ok(seed)
+    }
+
+    /**
+     * Append a record to the log. If this fails, the log may end with part of the record, so
+     * nothing more is appended until a checkpoint has replaced it.
+     */
+    pub fn append(&mut self, record: &Record) -> io::Result<()> {
+        let bytes = rmp_serde::to_vec(record).expect("Record could not be encoded");
+        let sealed = self.sealer.seal(LOG_FILE, self.generation, self.log_records, bytes);
+        let mut log = self.log.take()
+            .ok_or_else(|| io::Error::other("Storage needs a checkpoint"))?;
+        log.write_all(&frame(&sealed))?;
+        log.sync_data()?;
+        self.log_records += 1;
+        self.write_log_end()?;
+        self.log = Some(log);
+        Ok(())
+    }
+
+    pub fn needs_checkpoint(&self) -> bool {
+        self.log.is_none() || self.log_records >= CHECKPOINT_INTERVAL
+    }
+
+    /**
+     * Replace the stored records with a checkpoint of the whole store, and start a new log. If
+     * this fails part way through, nothing more can be appended until a checkpoint succeeds.
+     */
+    pub fn checkpoint(&mut self, records: &[Record]) -> io::Result<()> {
+        let records = records.iter()
+            .map(|r| rmp_serde::to_vec(r).expect("Record could not be encoded"))
+            .collect();
+
+        self.log = None;
+        write_file(&self.dir.join(CHECKPOINT_FILE), &self.sealer, self.generation + 1, records, true)?;
+        self.generation += 1;
+        self.start_log()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    /** A fresh directory, removed again when the test is done. */
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("braid-storage-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn rule(n: usize) -> Record {
+        Record::OwnershipRule(format!("r{}/:agent", n))
+    }
+
+    fn names(records: &[Record]) -> Vec<String> {
+        records.iter().map(|r| match r {
+            Record::OwnershipRule(pattern) => pattern.clone(),
+            other => panic!("Unexpected record {:?}", other),
+        }).collect()
+    }
+
+    fn expected(range: std::ops::Range<usize>) -> Vec<String> {
+        names(&range.map(rule).collect::<Vec<Record>>())
+    }
+
+    fn write(dir: &Path, key: Option<[u8; 32]>, range: std::ops::Range<usize>) {
+        let (mut storage, _) = Storage::open(dir, key).unwrap();
+        for n in range { storage.append(&rule(n)).unwrap(); }
+    }
+
+    fn modify(path: &Path, f: impl FnOnce(&mut Vec<u8>)) {
+        let mut data = fs::read(path).unwrap();
+        f(&mut data);
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new("round-trip");
+        write(&dir.0, Some(KEY), 0..3);
+
+        let (mut storage, records) = Storage::open(&dir.0, Some(KEY)).unwrap();
+        assert_eq!(names(&records), expected(0..3));
+        storage.checkpoint(&records).unwrap();
+        storage.append(&rule(3)).unwrap();
+        drop(storage);
+
+        let (_, records) = Storage::open(&dir.0, Some(KEY)).unwrap();
+        assert_eq!(names(&records), expected(0..4));
+    }
+
+    #[test]
+    fn torn_tail_truncated() {
+        let dir = TempDir::new("torn-tail");
+        write(&dir.0, Some(KEY), 0..2);
+        let log = dir.0.join(LOG_FILE);
+        let len = fs::metadata(&log).unwrap().len();
+        // Part of a third record, which claims to be 50 bytes long.
+        modify(&log, |data| data.extend_from_slice(&[0, 0, 0, 50, 1, 2, 3]));
+
+        let (mut storage, records) = Storage::open(&dir.0, Some(KEY)).unwrap();
+        assert_eq!(names(&records), expected(0..2));
+        assert_eq!(fs::metadata(&log).unwrap().len(), len);
+        storage.append(&rule(2)).unwrap();
+        drop(storage);
+
+        let (_, records) = Storage::open(&dir.0, Some(KEY)).unwrap();
+        assert_eq!(names(&records), expected(0..3));
+    }
+
+    #[test]
+    fn missing_records_detected() {
+        let dir = TempDir::new("missing-records");
+        write(&dir.0, Some(KEY), 0..1);
+        let log = dir.0.join(LOG_FILE);
+        let len = fs::metadata(&log).unwrap().len();
+        write(&dir.0, Some(KEY), 1..2);
+
+        // Dropping a whole record from the end of the log isn't a torn write.
+        OpenOptions::new().write(true).open(&log).unwrap().set_len(len).unwrap();
+        assert!(Storage::open(&dir.0, Some(KEY)).is_err());
+    }
+
+    #[test]
+    fn tampering_detected() {
+        let dir = TempDir::new("tampering");
+        write(&dir.0, Some(KEY), 0..2);
+        let log = dir.0.join(LOG_FILE);
+        let original = fs::read(&log).unwrap();
+
+        modify(&log, |data| *data.last_mut().unwrap() ^= 1);
+        let err = Storage::open(&dir.0, Some(KEY)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Records can't be moved between generations either.
+        fs::write(&log, &original).unwrap();
+        let (mut storage, records) = Storage::open(&dir.0, Some(KEY)).unwrap();
+        storage.checkpoint(&records).unwrap();
+        drop(storage);
+        let checkpoint = dir.0.join(CHECKPOINT_FILE);
+        modify(&checkpoint, |data| data[HEADER_LEN - 1] ^= 1);
+        assert!(Storage::open(&dir.0, Some(KEY)).is_err());
+    }
+
+    #[test]
+    fn wrong_key() {
+        let dir = TempDir::new("wrong-key");
+        write(&dir.0, Some(KEY), 0..1);
+
+        let err = Storage::open(&dir.0, Some([8; 32])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(Storage::open(&dir.0, None).is_err());
+        assert!(Storage::open(&dir.0, Some(KEY)).is_ok());
+    }
+
+    #[test]
+    fn plain_files_need_no_key() {
+        let dir = TempDir::new("plain");
+        write(&dir.0, None, 0..2);
+        assert!(Storage::open(&dir.0, Some(KEY)).is_err());
+
+        let (_, records) = Storage::open(&dir.0, None).unwrap();
+        assert_eq!(names(&records), expected(0..2));
+    }
+}
@@ -0,0 +1,234 @@
+use crate::types::*;
+use crate::op_db::OpDb;
+use crate::view_db::{ViewDb, DocChange};
+use crate::links::{self, Link, Visited};
+use crate::index::IndexQuery;
+use crate::computed::ComputedViews;
+use crate::capability::{self, Capability, Access};
+use async_std::channel::Sender;
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+/** What a subscriber is watching. */
+#[derive(Debug)]
+pub(crate) enum Target {
+    /**
+     * A document with links resolved to depth (see links::resolve). The subscriber gets a new
+     * snapshot whenever the document or any document transcluded into it changes.
+     */
+    Resolve { link: Link, depth: usize, visited: Visited },
+    /** All documents with keys starting with prefix. */
+    Prefix { prefix: String },
+    /** All documents matching a query on the named index. */
+    Index { name: String, query: IndexQuery },
+    /**
+     * The named computed view of a document. The output is rendered again whenever the document
+     * or the view's script changes.
+     */
+    View { name: String, key: DocId },
+}
+
+/** The parts of the database subscriptions are computed from. */
+pub(crate) struct Sources<'a> {
+    pub ops: &'a OpDb,
+    pub view: &'a ViewDb,
+    pub computed: &'a ComputedViews,
+}
+
+#[derive(Debug)]
+struct Subscription {
+    target: Target,
+    /** The subscriber's capability. This is checked again for every update. */
+    cap: Capability,
+    sender: Sender<Vec<u8>>,
+}
+
+/**
+ * Open subscriptions. Updates are streamed to subscribers as a series of frames, each with a
+ * small header block like an HTTP response. Subscriptions are dropped once the subscriber goes
+ * away.
+ *
+ * Query subscriptions (Prefix and Index) start with a snapshot of the result set, and then get
+ * add, update and remove events as documents enter, change within and leave it. Events are
+ * computed from each change's old and new values, so queries are never re-run.
+ */
+#[derive(Debug, Default)]
+pub(crate) struct Subscriptions {
+    subs: Vec<Subscription>,
+}
+
+/** Encode an update frame. */
+pub(crate) fn frame(version: Option<&str>, body: &[u8]) -> Vec<u8> {
+    let mut frame = match version {
+        Some(version) => format!("version: {}\r\n", version).into_bytes(),
+        None => Vec::new(),
+    };
+    frame.extend_from_slice(format!("content-length: {}\r\n\r\n", body.len()).as_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+fn json_body(value: &Value) -> Vec<u8> {
+    serde_json::to_vec(value).unwrap()
+}
+
+fn values_json(vals: &DbValue) -> Value {
+    vals.iter().map(|v| v.value.to_json()).collect()
+}
+
+fn event(name: &str, key: &DocId, vals: Option<&DbValue>) -> Value {
+    let mut event = serde_json::json!({ "event": name, "key": key });
+    if let Some(vals) = vals { event["values"] = values_json(vals); }
+    event
+}
+
+/**
+ * The event for a change to a document, given whether it was in the result set before and after
+ * the change.
+ */
+fn membership_event(change: &DocChange, was_in: bool, is_in: bool) -> Option<Value> {
+    match (was_in, is_in) {
+        (false, true) => Some(event("add", &change.key, Some(&change.new))),
+        (true, true) if change.old != change.new => Some(event("update", &change.key, Some(&change.new))),
+        (true, false) => Some(event("remove", &change.key, None)),
+        _ => None,
+    }
+}
+
+fn exists(vals: &DbValue) -> bool {
+    vals.iter().any(|v| v.value != DocValue::None)
+}
+
+fn values_of(vals: &DbValue) -> Vec<DocValue> {
+    vals.iter().map(|v| v.value.clone()).collect()
+}
+
+impl Target {
+    /** Compute the subscriber's current view of the target, updating any state it depends on. */
+    fn snapshot(&mut self, db: &Sources) -> Vec<u8> {
+        match self {
+            Target::Resolve { link, depth, visited } => {
+                *visited = Visited::default();
+                json_body(&links::resolve(db.ops, db.view, link, *depth, visited))
+            },
+            Target::Prefix { prefix } => {
+                let docs: Vec<Value> = db.view.list(prefix, None)
+                    .map(|(key, vals)| serde_json::json!({ "key": key, "values": values_json(&vals) }))
+                    .collect();
+                json_body(&serde_json::json!({ "event": "snapshot", "docs": docs }))
+            },
+            Target::Index { name, query } => {
+                let keys: BTreeSet<&DocId> = db.view.indexes.get(name).into_iter()
+                    .flat_map(|index| index.query(query))
+                    .map(|(_, key)| key)
+                    .collect();
+                let docs: Vec<Value> = keys.into_iter()
+                    .map(|key| serde_json::json!({ "key": key, "values": values_json(&db.view.get_resolved(key)) }))
+                    .collect();
+                json_body(&serde_json::json!({ "event": "snapshot", "docs": docs }))
+            },
+            Target::View { name, key } => match db.computed.render(db.ops, db.view, name, key) {
+                Some(Ok(rendered)) => rendered.body,
+                Some(Err(e)) => json_body(&serde_json::json!({ "error": e })),
+                None => json_body(&serde_json::json!({ "error": format!("No view named {}", name) })),
+            },
+        }
+    }
+
+    /**
+     * Check a capability lets the subscriber see everything the target shows. Resolve targets
+     * are checked against every document read by the last snapshot, including pinned links.
+     */
+    fn permitted(&self, db: &Sources, cap: &Capability, now: u64) -> bool {
+        let allows = |key: &str| cap.allows(Access::Subscribe, key, now);
+        match self {
+            Target::Resolve { link, visited, .. } => allows(&link.key) && visited.touched.iter().all(|key| allows(key)),
+            Target::Prefix { prefix } => allows(prefix),
+            Target::Index { name, .. } => db.view.indexes.get(name).is_some_and(|index| allows(&index.prefix)),
+            Target::View { name, key } => allows(key)
+                && db.computed.get(name).is_some_and(|def| allows(&def.script)),
+        }
+    }
+
+    /** The updates to send the subscriber for a set of changes. */
+    fn updates(&mut self, db: &Sources, changes: &[DocChange]) -> Vec<Vec<u8>> {
+        match self {
+            Target::Resolve { visited, .. } => {
+                if changes.iter().any(|c| visited.deps.contains(&c.key)) {
+                    vec!(self.snapshot(db))
+                } else { vec!() }
+            },
+            Target::Prefix { prefix } => changes.iter()
+                .filter(|c| c.key.starts_with(prefix.as_str()))
+                .filter_map(|c| membership_event(c, exists(&c.old), exists(&c.new)))
+                .map(|event| json_body(&event))
+                .collect(),
+            Target::Index { name, query } => {
+                let index = match db.view.indexes.get(name) {
+                    Some(index) => index,
+                    None => return vec!(),
+                };
+                let matches = |vals: &DbValue| index.values_for(&values_of(vals)).iter().any(|v| query.matches(v));
+
+                changes.iter()
+                    .filter(|c| index.covers(&c.key))
+                    .filter_map(|c| membership_event(c, matches(&c.old), matches(&c.new)))
+                    .map(|event| json_body(&event))
+                    .collect()
+            },
+            Target::View { name, key } => {
+                if changes.iter().any(|c| db.computed.depends_on(name, key, &c.key)) {
+                    vec!(self.snapshot(db))
+                } else { vec!() }
+            },
+        }
+    }
+}
+
+impl Subscriptions {
+    /**
+     * Add a subscription. The subscriber is sent the current state of the target first. Returns
+     * false if the capability doesn't allow the subscription.
+     */
+    pub(crate) fn add(&mut self, db: &Sources, version: &str, mut target: Target, cap: Capability, sender: Sender<Vec<u8>>) -> bool {
+        let snapshot = target.snapshot(db);
+        if !target.permitted(db, &cap, capability::now()) { return false; }
+
+        if sender.try_send(frame(Some(version), &snapshot)).is_ok() {
+            self.subs.push(Subscription { target, cap, sender });
+        }
+        true
+    }
+
+    /**
+     * Send a fresh snapshot to subscribers of a computed view, after its definition has changed.
+     * Subscriptions end if the subscriber can't read the new script.
+     */
+    pub(crate) fn view_changed(&mut self, db: &Sources, version: &str, view: &str) {
+        let now = capability::now();
+        self.subs.retain_mut(|sub| {
+            if sub.sender.is_closed() { return false; }
+            if !matches!(&sub.target, Target::View { name, .. } if name == view) { return true; }
+
+            let snapshot = sub.target.snapshot(db);
+            sub.target.permitted(db, &sub.cap, now)
+                && sub.sender.try_send(frame(Some(version), &snapshot)).is_ok()
+        });
+    }
+
+    /**
+     * Send updates to subscribers affected by a set of changes. Subscriptions end once the
+     * subscriber's capability no longer allows them (eg because it has expired).
+     */
+    pub(crate) fn notify(&mut self, db: &Sources, version: &str, changes: &[DocChange]) {
+        let now = capability::now();
+        self.subs.retain_mut(|sub| {
+            if sub.sender.is_closed() { return false; }
+
+            let updates = sub.target.updates(db, changes);
+            if !sub.target.permitted(db, &sub.cap, now) { return false; }
+            updates.iter()
+                .all(|update| sub.sender.try_send(frame(Some(version), update)).is_ok())
+        });
+    }
+}
@@ -0,0 +1,178 @@
+use crate::types::*;
+use crate::rga::{Rga, ItemId};
+use std::fmt;
+use serde::{Serialize, Deserialize};
+
+/**
+ * Text CRDT operations. These are generated server-side from positional edits (see edit_ops),
+ * because they name the items they're inserted after or deleting.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TextOp {
+    Insert { origin: Option<ItemId>, content: String },
+    Delete(Vec<ItemId>),
+}
+
+/** A replicated text document. This is an RGA of characters. */
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextDoc {
+    chars: Rga<char>,
+}
+
+impl TextDoc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.chars.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    /** Merge another replica's state into this one. */
+    pub fn merge(&mut self, other: &TextDoc) {
+        self.chars.merge(&other.chars);
+    }
+
+    /**
+     * Convert a positional edit (in characters) into text operations. The edit deletes `remove`
+     * characters at `pos`, then inserts `insert` at `pos`.
+     */
+    pub fn edit_ops(&self, pos: usize, remove: usize, insert: &str) -> Result<Vec<TextOp>, String> {
+        let visible = self.chars.visible_ids();
+        let end = pos.checked_add(remove).filter(|end| *end <= visible.len())
+            .ok_or_else(|| format!("Edit of {} characters at {} is out of bounds", remove, pos))?;
+
+        let mut ops = Vec::new();
+        if remove > 0 {
+            ops.push(TextOp::Delete(visible[pos..end].iter().map(|id| (*id).clone()).collect()));
+        }
+        if !insert.is_empty() {
+            ops.push(TextOp::Insert {
+                origin: if pos == 0 { None } else { Some(visible[pos - 1].clone()) },
+                content: insert.to_string()
+            });
+        }
+        Ok(ops)
+    }
+
+    /** Generate operations to change this document's content to new_content. */
+    pub fn diff_ops(&self, new_content: &str) -> Vec<TextOp> {
+        let old: Vec<char> = self.chars.visible().copied().collect();
+        let new: Vec<char> = new_content.chars().collect();
+
+        let prefix = old.iter().zip(new.iter()).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b).count();
+
+        let inserted: String = new[prefix..new.len() - suffix].iter().collect();
+        self.edit_ops(prefix, old.len() - suffix - prefix, &inserted).unwrap()
+    }
+
+    /** Apply operations from the patch written by the operation with the specified version. */
+    pub fn apply(&mut self, ops: &[TextOp], version: &RemoteVersion) -> Result<(), String> {
+        let lamport = self.chars.max_lamport() + 1;
+        let mut offset = 0;
+
+        for op in ops {
+            match op {
+                TextOp::Insert { origin, content } => {
+                    let mut origin = origin.clone();
+                    for ch in content.chars() {
+                        let id = ItemId::new(version, offset);
+                        self.chars.insert(id.clone(), origin, lamport, ch)?;
+                        // Each character in a run is inserted after the previous one.
+                        origin = Some(id);
+                        offset += 1;
+                    }
+                },
+                TextOp::Delete(ids) => {
+                    for id in ids {
+                        self.chars.delete(id)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TextDoc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for ch in self.chars.visible() {
+            write!(f, "{}", ch)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(agent: &str, seq: Seq) -> RemoteVersion {
+        RemoteVersion { agent: agent.to_string(), seq }
+    }
+
+    /** Apply a positional edit to a copy of doc, as the operation with the specified version. */
+    fn edit(doc: &TextDoc, pos: usize, remove: usize, insert: &str, version: &RemoteVersion) -> TextDoc {
+        let ops = doc.edit_ops(pos, remove, insert).unwrap();
+        let mut doc = doc.clone();
+        doc.apply(&ops, version).unwrap();
+        doc
+    }
+
+    fn merged(a: &TextDoc, b: &TextDoc) -> TextDoc {
+        let mut doc = a.clone();
+        doc.merge(b);
+        doc
+    }
+
+    #[test]
+    fn concurrent_inserts_converge() {
+        let base = edit(&TextDoc::new(), 0, 0, "hello", &version("a", 0));
+        let b = edit(&base, 5, 0, " world", &version("b", 0));
+        let c = edit(&edit(&base, 5, 0, "!", &version("c", 0)), 0, 0, "oh, ", &version("c", 1));
+
+        let (bc, cb) = (merged(&b, &c), merged(&c, &b));
+        assert_eq!(bc, cb);
+        assert_eq!(bc.to_string(), "oh, hello! world");
+    }
+
+    #[test]
+    fn concurrent_delete_and_insert_converge() {
+        let base = edit(&TextDoc::new(), 0, 0, "abc", &version("a", 0));
+        let b = edit(&base, 1, 1, "", &version("b", 0));
+        // Inserted after the character b deletes, so it has to find its place from a tombstone.
+        let c = edit(&base, 2, 0, "X", &version("c", 0));
+
+        let (bc, cb) = (merged(&b, &c), merged(&c, &b));
+        assert_eq!(bc, cb);
+        assert_eq!(bc.to_string(), "aXc");
+    }
+
+    #[test]
+    fn concurrent_deletes_converge() {
+        let base = edit(&TextDoc::new(), 0, 0, "abcd", &version("a", 0));
+        let b = edit(&base, 0, 3, "", &version("b", 0));
+        let c = edit(&base, 1, 3, "", &version("c", 0));
+
+        assert_eq!(merged(&b, &c), merged(&c, &b));
+        assert_eq!(merged(&b, &c).to_string(), "");
+        // Merging is idempotent, and merging an older state changes nothing.
+        assert_eq!(merged(&b, &b), b);
+        assert_eq!(merged(&b, &base), b);
+    }
+
+    #[test]
+    fn diff_ops() {
+        let base = edit(&TextDoc::new(), 0, 0, "the cat sat", &version("a", 0));
+        let ops = base.diff_ops("the dog sat");
+        let mut doc = base.clone();
+        doc.apply(&ops, &version("a", 1)).unwrap();
+        assert_eq!(doc.to_string(), "the dog sat");
+    }
+}
@@ -1,10 +1,21 @@
 
+use crate::patch::JsonPatchOp;
+use crate::text_crdt::{TextDoc, TextOp};
+use crate::json_crdt::{JsonDoc, JsonOp, RegisterMode};
+use crate::counter_crdt::Counter;
+use crate::set_crdt::{OrSet, SetOp};
+use crate::owned::{OwnedDoc, Action};
+use crate::rga::ItemId;
+use serde::{Serialize, Deserialize};
+
 pub type Order = u64;
 pub type Seq = u64;
 pub type DocId = String;
 pub type Agent = u32;
+/** SHA-256 hash of an operation and its history. See merkle::hash_op. */
+pub type OpHash = [u8; 32];
 
-#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 pub struct RemoteVersion {
     pub agent: String,
     pub seq: Seq
@@ -17,17 +28,22 @@ pub struct LocalVersion {
     pub seq: Seq
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RemoteOperation {
     pub version: RemoteVersion,
     /** Usually version.seq - 1. This allows sparse versions. u64 max for first version. */
     pub succeeds: Option<Seq>,
 
     pub parents: Vec<RemoteVersion>,
-    pub doc_ops: Vec<RemoteDocOp>
+    pub doc_ops: Vec<RemoteDocOp>,
+
+    /** Hash of the operation and its parents' hashes (see merkle::hash_op). */
+    pub hash: OpHash,
+    /** The agent's ed25519 signature of the rest of the operation (see signing::signed_bytes). */
+    pub signature: Vec<u8>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RemoteDocOp {
     pub id: DocId,
     pub patch: DocPatch,
@@ -43,19 +59,77 @@ pub(crate) struct LocalOperation {
 
     /** Order of previous version from this agent. Not sure if this is necessary... */
     pub succeeds: Option<Order>,
+
+    pub hash: OpHash,
+    /** Kept so the operation can be sent on to other peers. */
+    pub signature: Vec<u8>,
+    /**
+     * Some of the operation's patches have been pruned (see OpDb::prune), so it no longer matches
+     * its hash and signature and can't be sent to peers.
+     */
+    pub pruned: bool,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub(crate) struct LocalDocOp {
     pub id: DocId,
-    pub patch: DocPatch,
+    /** None if the patch was discarded when history was pruned. */
+    pub patch: Option<DocPatch>,
     pub parents: Vec<Order>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+/**
+ * An operation with pruned history, as it's stored. Pruned operations can't be verified, so
+ * they're only read from our own storage.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PrunedOperation {
+    pub version: RemoteVersion,
+    pub succeeds: Option<Seq>,
+    pub parents: Vec<RemoteVersion>,
+    pub doc_ops: Vec<PrunedDocOp>,
+    pub hash: OpHash,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PrunedDocOp {
+    pub id: DocId,
+    pub patch: Option<DocPatch>,
+    pub parents: Vec<RemoteVersion>,
+}
+
+impl From<&RemoteOperation> for PrunedOperation {
+    fn from(op: &RemoteOperation) -> Self {
+        PrunedOperation {
+            version: op.version.clone(),
+            succeeds: op.succeeds,
+            parents: op.parents.clone(),
+            doc_ops: op.doc_ops.iter().map(|doc_op| PrunedDocOp {
+                id: doc_op.id.clone(),
+                patch: Some(doc_op.patch.clone()),
+                parents: doc_op.parents.clone(),
+            }).collect(),
+            hash: op.hash,
+            signature: op.signature.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DocValue {
     None,
-    Blob(Vec<u8>),
+    /** Opaque bytes, stored along with the content type they were written with. */
+    Blob { content_type: String, data: Vec<u8> },
+    /** Structured values. The server can look inside these. */
+    Json(serde_json::Value),
+    /** Collaboratively edited text. Concurrent versions are merged rather than conflicting. */
+    Text(TextDoc),
+    /** JSON with field level merging of concurrent edits. */
+    JsonCrdt(JsonDoc),
+    Counter(Counter),
+    Set(OrSet),
+    Owned(OwnedDoc),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -65,8 +139,32 @@ pub(crate) struct DbValueSingle {
 }
 pub(crate) type DbValue = Vec<DbValueSingle>;
 
-type DocPatch = DocValue;
-// #[derive(Clone, Debug, PartialEq, Eq)]
-// pub enum DocPatch {
-//     Replace(DocValue),
-// }
\ No newline at end of file
+/**
+ * Doc ops carry a patch against the document's value at the doc op's parents. Replace ignores the
+ * parents. Patches to CRDT documents are applied to the merged value of all their parents, and
+ * everything else needs exactly one parent.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DocPatch {
+    Replace(DocValue),
+    /** RFC 7386 JSON merge patch */
+    MergePatch(serde_json::Value),
+    /** RFC 6902 JSON patch */
+    JsonPatch(Vec<JsonPatchOp>),
+    /** Replace `remove` bytes at `pos` in a blob with `insert` */
+    Splice { pos: usize, remove: usize, insert: Vec<u8> },
+    /** Edits to a text CRDT document. If the parent is empty this creates the document. */
+    Text(Vec<TextOp>),
+    /**
+     * Edits to a JSON CRDT document. The mode is used if this patch creates the document, and
+     * must match the document's mode otherwise.
+     */
+    JsonCrdt { mode: RegisterMode, ops: Vec<JsonOp> },
+    /** Add to a counter. Negative amounts decrement. */
+    Counter(i64),
+    Set(Vec<SetOp>),
+    /** Apply (or propose, if we aren't the owner) an action on an owned document. */
+    Action(Action),
+    /** Apply pending actions on an owned document in this order. Only valid from the owner. */
+    Sequence(Vec<ItemId>),
+}
@@ -18,6 +18,7 @@ impl AgentMap {
         Self::default()
     }
 
+    #[allow(clippy::wrong_self_convention)]
     fn to_local(&mut self, ext: &str) -> Agent {
         if ext == ROOT_AGENT_STR { return ROOT_AGENT; }
 
@@ -33,7 +34,7 @@ impl AgentMap {
         })
     }
 
-    fn try_to_local(&self, ext: &str) -> Option<Agent> {
+    pub(crate) fn try_to_local(&self, ext: &str) -> Option<Agent> {
         if ext == ROOT_AGENT_STR { return Some(ROOT_AGENT); }
         self.remote_to_local.get(ext).cloned()
     }
@@ -44,7 +45,63 @@ impl AgentMap {
     }
 }
 
+/**
+ * Maps local versions to orders. An agent's operations usually have consecutive seqs and are
+ * usually added one after another, so they have consecutive orders too. Rather than storing an
+ * entry per operation, this stores runs, each mapping seqs start..start+len of an agent to orders
+ * order..order+len.
+ */
+#[derive(Debug, Default)]
+pub(crate) struct VersionRuns {
+    /** Keyed by the first version in each run. */
+    runs: BTreeMap<LocalVersion, Run>,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Run {
+    len: u64,
+    order: Order,
+}
+
+impl VersionRuns {
+    /** Find the run containing a version, along with the version it starts at. */
+    fn run_containing(&self, version: &LocalVersion) -> Option<(LocalVersion, Run)> {
+        let (start, run) = self.runs.range(..=version).next_back()?;
+        if start.agent == version.agent && version.seq - start.seq < run.len {
+            Some((*start, *run))
+        } else { None }
+    }
+
+    pub(crate) fn get(&self, version: &LocalVersion) -> Option<Order> {
+        self.run_containing(version)
+            .map(|(start, run)| run.order + (version.seq - start.seq))
+    }
+
+    /** The largest known seq from the agent. None if we have no versions from it. */
+    pub(crate) fn max_seq(&self, agent: Agent) -> Option<Seq> {
+        let end = LocalVersion { agent, seq: Seq::MAX };
+        let (start, run) = self.runs.range(..=end).next_back()?;
+        if start.agent == agent { Some(start.seq + (run.len - 1)) } else { None }
+    }
+
+    /** Add a version. It must not already be in the map. */
+    pub(crate) fn insert(&mut self, version: LocalVersion, order: Order) {
+        if version.seq > 0 {
+            // Extend the run which ends just before this version, if its orders line up too.
+            let prev = LocalVersion { agent: version.agent, seq: version.seq - 1 };
+            if let Some((start, run)) = self.run_containing(&prev) {
+                if start.seq + run.len == version.seq && run.order + run.len == order {
+                    self.runs.get_mut(&start).unwrap().len += 1;
+                    return;
+                }
+            }
+        }
+        self.runs.insert(version, Run { len: 1, order });
+    }
+}
+
 impl LocalVersion {
+    #[allow(clippy::wrong_self_convention)]
     pub(crate) fn to_remote(&self, agent_map: &AgentMap) -> RemoteVersion {
         RemoteVersion {
             agent: agent_map.to_remote(self.agent).to_string(),
@@ -64,6 +121,7 @@ impl RemoteVersion {
             })
     }
 
+    #[allow(clippy::wrong_self_convention)]
     pub(crate) fn to_local_mut(&self, agent_map: &mut AgentMap) -> LocalVersion {
         LocalVersion {
             agent: agent_map.to_local(&self.agent),
@@ -76,5 +134,127 @@ impl RemoteVersion {
         buf.extend(&self.seq.to_be_bytes());
         base64::encode(buf)
     }
+
+    /** Inverse of encode. Returns None if the string isn't a valid encoded version. */
+    pub(crate) fn decode(encoded: &str) -> Option<RemoteVersion> {
+        let buf = base64::decode(encoded).ok()?;
+        if buf.len() < 8 { return None; }
+
+        let (agent, seq) = buf.split_at(buf.len() - 8);
+        let mut seq_bytes = [0u8; 8];
+        seq_bytes.copy_from_slice(seq);
+
+        Some(RemoteVersion {
+            agent: String::from_utf8(agent.to_vec()).ok()?,
+            seq: Seq::from_be_bytes(seq_bytes)
+        })
+    }
 }
 
+/**
+ * Encode a set of versions (eg a document's current heads) as a single string. The base64
+ * alphabet doesn't contain '.', so we use that as a separator. This is used for ETags.
+ */
+pub(crate) fn encode_versions(versions: &[RemoteVersion]) -> String {
+    versions.iter()
+        .map(|v| v.encode())
+        .collect::<Vec<String>>()
+        .join(".")
+}
+
+pub(crate) fn decode_versions(encoded: &str) -> Option<Vec<RemoteVersion>> {
+    encoded.split('.')
+        .map(RemoteVersion::decode)
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(agent: Agent, seq: Seq) -> LocalVersion {
+        LocalVersion { agent, seq }
+    }
+
+    /** Insert versions in order, giving them consecutive orders. */
+    fn runs_of(versions: &[LocalVersion]) -> VersionRuns {
+        let mut runs = VersionRuns::default();
+        for (order, version) in versions.iter().enumerate() {
+            runs.insert(*version, order as Order);
+        }
+        runs
+    }
+
+    fn check(runs: &VersionRuns, versions: &[LocalVersion]) {
+        for (order, version) in versions.iter().enumerate() {
+            assert_eq!(runs.get(version), Some(order as Order), "{:?}", version);
+        }
+    }
+
+    #[test]
+    fn consecutive_seqs_share_a_run() {
+        let versions: Vec<LocalVersion> = (0..5).map(|seq| v(0, seq)).collect();
+        let runs = runs_of(&versions);
+
+        assert_eq!(runs.runs.len(), 1);
+        check(&runs, &versions);
+        assert_eq!(runs.get(&v(0, 5)), None);
+        assert_eq!(runs.get(&v(1, 0)), None);
+        assert_eq!(runs.max_seq(0), Some(4));
+    }
+
+    #[test]
+    fn interleaved_agents() {
+        // Each agent's seqs are consecutive, but their orders aren't, so nothing can be merged.
+        let versions = [v(0, 0), v(1, 0), v(0, 1), v(1, 1), v(0, 2)];
+        let runs = runs_of(&versions);
+
+        assert_eq!(runs.runs.len(), 5);
+        check(&runs, &versions);
+        assert_eq!(runs.max_seq(0), Some(2));
+        assert_eq!(runs.max_seq(1), Some(1));
+        assert_eq!(runs.max_seq(2), None);
+    }
+
+    #[test]
+    fn sparse_seqs() {
+        let versions = [v(1, 0), v(1, 5), v(1, 6), v(1, 10)];
+        let runs = runs_of(&versions);
+
+        assert_eq!(runs.runs.len(), 3);
+        check(&runs, &versions);
+        for seq in [1, 4, 7, 9, 11] {
+            assert_eq!(runs.get(&v(1, seq)), None);
+        }
+        assert_eq!(runs.max_seq(0), None);
+        assert_eq!(runs.max_seq(1), Some(10));
+        assert_eq!(runs.max_seq(2), None);
+    }
+
+    #[test]
+    fn out_of_order_seqs() {
+        let versions = [v(0, 3), v(0, 1), v(0, 2), v(0, 0), v(0, 4)];
+        let runs = runs_of(&versions);
+
+        check(&runs, &versions);
+        assert_eq!(runs.max_seq(0), Some(4));
+        assert_eq!(runs.get(&v(0, 5)), None);
+    }
+
+    #[test]
+    fn orders_which_skip() {
+        // Consecutive seqs whose orders aren't consecutive get runs of their own.
+        let mut runs = VersionRuns::default();
+        runs.insert(v(0, 0), 0);
+        runs.insert(v(0, 1), 1);
+        runs.insert(v(0, 2), 5);
+        runs.insert(v(0, 3), 6);
+
+        assert_eq!(runs.runs.len(), 2);
+        assert_eq!(runs.get(&v(0, 1)), Some(1));
+        assert_eq!(runs.get(&v(0, 2)), Some(5));
+        assert_eq!(runs.get(&v(0, 3)), Some(6));
+        assert_eq!(runs.max_seq(0), Some(3));
+    }
+}
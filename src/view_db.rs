@@ -1,29 +1,95 @@
 use std::collections::BTreeMap;
+use std::ops::Bound;
 use crate::types::*;
-use crate::{ROOT_ORDER, DEEP_CHECK, doc_op_entry};
+use crate::{ROOT_ORDER, DEEP_CHECK};
 use crate::op_db::OpDb;
+use crate::patch::merge_values;
+use crate::index::Index;
+use crate::links::Backlinks;
+use crate::derived::{Derivations, Output, OutputChange};
+use crate::error::DbError;
+
+/**
+ * Deleting a document writes a tombstone (DocValue::None). When a delete is concurrent with an
+ * edit, both versions are kept in the document's value. This decides which one readers see.
+ *
+ * The policy applies to the whole database, and is set at startup (BRAID_DELETE_POLICY). Every
+ * peer needs the same policy, or they'll show different values for the same versions.
+ */
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DeletePolicy {
+    DeleteWins,
+    EditWins,
+}
+
+impl DeletePolicy {
+    pub fn parse(name: &str) -> Option<DeletePolicy> {
+        match name {
+            "delete-wins" => Some(DeletePolicy::DeleteWins),
+            "edit-wins" => Some(DeletePolicy::EditWins),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ViewDb {
     pub(crate) branch: Vec<Order>,
     docs: BTreeMap<DocId, DbValue>,
+    pub(crate) delete_policy: DeletePolicy,
+    /** Secondary indexes by name. These are kept up to date as documents change. */
+    pub(crate) indexes: BTreeMap<String, Index>,
+    pub(crate) backlinks: Backlinks,
+    pub(crate) derived: Derivations,
+}
+
+/**
+ * A change to a document's value, returned when operations are applied to the view. Values are
+ * resolved (see get_resolved).
+ */
+#[derive(Clone, Debug)]
+pub struct DocChange {
+    pub key: DocId,
+    pub old: DbValue,
+    pub new: DbValue,
 }
 
 impl Default for ViewDb {
     fn default() -> Self {
         ViewDb {
             branch: vec!(ROOT_ORDER),
-            docs: BTreeMap::new()
+            docs: BTreeMap::new(),
+            delete_policy: DeletePolicy::EditWins,
+            indexes: BTreeMap::new(),
+            backlinks: Backlinks::default(),
+            derived: Derivations::default(),
         }
     }
 }
 
-impl ViewDb {
-    pub(crate) fn new() -> Self {
-        Self::default()
-    }
+fn values_of(vals: &DbValue) -> Vec<DocValue> {
+    vals.iter().map(|v| v.value.clone()).collect()
+}
+
+/** The order of the operation which wrote the newest of a document's versions. */
+fn version_order(vals: &DbValue) -> Order {
+    vals.iter().map(|v| v.order).filter(|o| *o != ROOT_ORDER).max().unwrap_or(ROOT_ORDER)
+}
+
+fn output_change((key, old, new): OutputChange) -> DocChange {
+    let value = |output: Option<Output>| vec!(match output {
+        Some((value, order)) => DbValueSingle { order, value: DocValue::Json(value) },
+        None => DbValueSingle { order: ROOT_ORDER, value: DocValue::None },
+    });
+    DocChange { key, old: value(old), new: value(new) }
+}
 
+impl ViewDb {
     pub(crate) fn get_cloned(&self, key: &DocId) -> DbValue {
+        if let Some((value, order)) = self.derived.get(key) {
+            return vec!(DbValueSingle { order: *order, value: DocValue::Json(value.clone()) });
+        }
+
         // Every document implicitly exists, with a null value.
         // TODO: Refactor to avoid .clone().
         self.docs.get(key).cloned().unwrap_or_else(|| {
@@ -34,23 +100,169 @@ impl ViewDb {
         })
     }
 
+    /**
+     * Get the value of a document with tombstones resolved using the delete policy. The returned
+     * value only contains tombstones if the document is deleted. Concurrent versions of CRDT
+     * documents are merged, but there may still be multiple (conflicting) values.
+     */
+    pub(crate) fn get_resolved(&self, key: &DocId) -> DbValue {
+        let vals = self.get_cloned(key);
+        let (tombstones, live): (DbValue, DbValue) = vals.into_iter()
+            .partition(|v| v.value == DocValue::None);
+
+        if live.is_empty() || (!tombstones.is_empty() && self.delete_policy == DeletePolicy::DeleteWins) {
+            tombstones
+        } else if live.len() > 1 && live.iter().all(|v| v.value.is_mergeable()) {
+            // Concurrent edits to CRDT documents aren't conflicts. Merge them.
+            let order = live.iter().map(|v| v.order).max().unwrap();
+            match merge_values(live.iter().map(|v| v.value.clone()).collect()) {
+                Ok(value) => vec!(DbValueSingle { order, value }),
+                Err(_) => live,
+            }
+        } else {
+            live
+        }
+    }
+
+    pub(crate) fn is_deleted(&self, key: &DocId) -> bool {
+        self.get_resolved(key).iter().all(|v| v.value == DocValue::None)
+    }
+
+    /**
+     * Iterate through all (non-deleted) documents whose key starts with prefix, in key order.
+     * If after is specified, iteration starts with the first key after it. Keys are stable, so
+     * the last key returned works as a pagination cursor.
+     */
+    pub(crate) fn list<'a>(&'a self, prefix: &'a str, after: Option<&DocId>) -> impl Iterator<Item=(&'a DocId, DbValue)> + 'a {
+        let start = match after {
+            Some(after) if after.as_str() >= prefix => Bound::Excluded(after.clone()),
+            _ => Bound::Included(prefix.to_string()),
+        };
+
+        self.docs.range((start, Bound::Unbounded))
+            .take_while(move |(key, _)| key.starts_with(prefix))
+            .map(move |(key, _)| (key, self.get_resolved(key)))
+            .filter(|(_, vals)| vals.iter().any(|v| v.value != DocValue::None))
+    }
+
+    /**
+     * Declare an index over the field at path (a JSON pointer) of documents with the specified key
+     * prefix. Declaring an index which already exists rebuilds it.
+     */
+    pub(crate) fn create_index(&mut self, name: &str, prefix: &str, path: &str) -> Result<(), String> {
+        if !path.is_empty() && !path.starts_with('/') {
+            return Err(format!("Invalid JSON pointer '{}'", path));
+        }
+
+        let mut index = Index::new(prefix.to_string(), path.to_string());
+        for (key, vals) in self.list(prefix, None) {
+            index.update(key, &vals.into_iter().map(|v| v.value).collect::<Vec<_>>());
+        }
+        self.indexes.insert(name.to_string(), index);
+        Ok(())
+    }
+
+    /**
+     * Declare a derivation (see Derivations) named name, which maps documents with keys starting
+     * with prefix to derived documents with keys starting with output.
+     */
+    pub(crate) fn create_derivation(&mut self, name: &str, deriver: &str, prefix: &str, output: &str) -> Result<Vec<DocChange>, String> {
+        self.derived.register(name, deriver, prefix, output)?;
+
+        let inputs: Vec<(DocId, DbValue)> = self.list(prefix, None)
+            .map(|(key, vals)| (key.clone(), vals))
+            .collect();
+        Ok(inputs.iter()
+            .flat_map(|(key, vals)| self.derived.update(key, &values_of(vals), version_order(vals)))
+            .map(output_change)
+            .collect())
+    }
+
+    /**
+     * Update index, backlink and derived document entries for a document which has changed.
+     * Returns changes to derived documents.
+     */
+    fn reindex(&mut self, key: &DocId, new: &DbValue) -> Vec<DocChange> {
+        let values = values_of(new);
+        for index in self.indexes.values_mut().filter(|index| index.covers(key)) {
+            index.update(key, &values);
+        }
+        self.backlinks.update(key, &values);
+
+        self.derived.update(key, &values, version_order(new)).into_iter().map(output_change).collect()
+    }
+
     // TODO:
     // fn get_remote_value(&self, key: &DocId) ->
 
-    pub(crate) fn branch_as_versions(&self, ops: &OpDb) -> Vec<LocalVersion> {
-        self.branch.iter().map(|o| {
-            ops.order_to_version(*o).clone()
-        }).collect()
+    /**
+     * Calculate the new value of a document from a patch written against the specified document
+     * versions by the operation with the specified version. Values of the document's current
+     * heads are used directly, and anything older is reconstructed from the op db.
+     */
+    pub(crate) fn patched_value(&self, ops: &OpDb, id: &DocId, parents: &[Order], patch: &DocPatch, version: &RemoteVersion) -> Result<DocValue, String> {
+        if let DocPatch::Replace(value) = patch { return Ok(value.clone()); }
+
+        let heads = self.docs.get(id);
+        let base = merge_values(parents.iter().map(|p| {
+            match heads.and_then(|vals| vals.iter().find(|v| v.order == *p)) {
+                Some(v) => Ok(v.value.clone()),
+                None => ops.doc_value_at(*p, id).map_err(|e| e.to_string()),
+            }
+        }).collect::<Result<Vec<DocValue>, String>>()?)?;
+
+        patch.apply(&base, version)
+    }
+
+    /**
+     * Check an agent may write a patch against the specified versions of a document. Owned
+     * documents can only be replaced or sequenced by their owner, and other agents can only
+     * propose actions. Owned documents can only be created by their owner.
+     */
+    pub(crate) fn check_owner(&self, ops: &OpDb, id: &DocId, parents: &[Order], patch: &DocPatch, version: &RemoteVersion) -> Result<(), DbError> {
+        let not_owner = |owner: &str| Err(DbError::NotOwner { key: id.clone(), owner: owner.to_string() });
+        match patch {
+            DocPatch::Action(_) => return Ok(()),
+            DocPatch::Replace(DocValue::Owned(doc)) if doc.owner != version.agent => return not_owner(&doc.owner),
+            _ => {},
+        }
+
+        let heads = self.docs.get(id);
+        for p in parents {
+            let owner = match heads.and_then(|vals| vals.iter().find(|v| v.order == *p)) {
+                Some(DbValueSingle { value: DocValue::Owned(doc), .. }) => Some(doc.owner.clone()),
+                Some(_) => None,
+                None => match ops.doc_value_at(*p, id)? {
+                    DocValue::Owned(doc) => Some(doc.owner),
+                    _ => None,
+                },
+            };
+            if let Some(owner) = owner.filter(|owner| *owner != version.agent) {
+                return not_owner(&owner);
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Get the current value of a document with all concurrent versions merged. This fails if the
+     * document has conflicting versions which can't be merged.
+     */
+    pub(crate) fn merged_value(&self, key: &DocId) -> Result<DocValue, String> {
+        merge_values(self.get_cloned(key).into_iter().map(|v| v.value).collect())
     }
 
-    pub(crate) fn apply_forwards(&mut self, ops: &OpDb, order: Order) {
+    pub(crate) fn apply_forwards(&mut self, ops: &OpDb, order: Order) -> Vec<DocChange> {
         let op = ops.operation_by_order(order);
+        let mut changes = Vec::with_capacity(op.doc_ops.len());
 
         let new_branch = ops.advance_branch_by_op(&self.branch[..], op);
         self.branch = new_branch;
+        let version = ops.order_to_remote_version(order);
 
         for doc_op in &op.doc_ops {
             let prev_vals = self.get_cloned(&doc_op.id);
+            let old = self.get_resolved(&doc_op.id);
 
             // The doc op's parents field contains a subset of the versions present in
             // oldVal.
@@ -74,9 +286,16 @@ impl ViewDb {
                 }
             }
 
+            let value = match &doc_op.patch {
+                Some(patch) => self.patched_value(ops, &doc_op.id, &doc_op.parents, patch, &version)
+                    .expect("Invalid patch in op db"),
+                // Only pruned operations replayed from storage are missing patches. Their values
+                // are superseded by later operations in the replay.
+                None => DocValue::None,
+            };
             let mut new_vals: DbValue = vec!(DbValueSingle {
                 order,
-                value: doc_op.patch.clone()
+                value
             });
 
             for old_entry in prev_vals {
@@ -92,13 +311,29 @@ impl ViewDb {
             self.docs.insert(doc_op.id.clone(), new_vals);
             // TODO: Should be a way to avoid the clone when updating.
             // *self.docs.get_mut(&doc_op.id).unwrap() = new_vals;
-
-            // TODO: And update listeners.
+            let new = self.get_resolved(&doc_op.id);
+            let derived = self.reindex(&doc_op.id, &new);
+            changes.push(DocChange { key: doc_op.id.clone(), old, new });
+            changes.extend(derived);
         }
+        changes
     }
 
-    pub(crate) fn apply_backwards(&mut self, ops: &OpDb, order: Order) {
+    /**
+     * Undo an operation. This needs the values of the document versions the operation replaced,
+     * so it fails (without changing anything) if their history has been pruned.
+     */
+    #[allow(dead_code)] // Nothing rewinds the view yet.
+    pub(crate) fn apply_backwards(&mut self, ops: &OpDb, order: Order) -> Result<Vec<DocChange>, DbError> {
         let op = ops.operation_by_order(order);
+        let mut changes = Vec::with_capacity(op.doc_ops.len());
+
+        let mut parent_values = BTreeMap::<(Order, &DocId), DocValue>::new();
+        for doc_op in &op.doc_ops {
+            for &p in doc_op.parents.iter().filter(|p| **p != ROOT_ORDER) {
+                parent_values.insert((p, &doc_op.id), ops.doc_value_at(p, &doc_op.id)?);
+            }
+        }
         // let prev_branch = self.branch;
 
         // Remove the operation from the branch.
@@ -121,6 +356,7 @@ impl ViewDb {
         // And update the data
         for doc_op in &op.doc_ops {
             let prev_vals = self.get_cloned(&doc_op.id);
+            let old = self.get_resolved(&doc_op.id);
 
             // The values should instead contain:
             // - Everything in prev_vals not including op.version
@@ -140,11 +376,9 @@ impl ViewDb {
                         // If all we have is the root, we'll delete the key.
                         assert!(new_vals.is_empty());
                     } else {
-                        let parent_op = ops.operation_by_order(*p);
-                        let parent_docop = doc_op_entry(&parent_op.doc_ops[..], &doc_op.id).unwrap();
                         new_vals.push(DbValueSingle {
                             order: *p,
-                            value: parent_docop.patch.clone()
+                            value: parent_values.remove(&(*p, &doc_op.id)).unwrap()
                         });
                     }
                 }
@@ -156,7 +390,12 @@ impl ViewDb {
             } else {
                 self.docs.insert(doc_op.id.clone(), new_vals);
             }
+            let new = self.get_resolved(&doc_op.id);
+            let derived = self.reindex(&doc_op.id, &new);
+            changes.push(DocChange { key: doc_op.id.clone(), old, new });
+            changes.extend(derived);
         }
+        Ok(changes)
     }
 }
 